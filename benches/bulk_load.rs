@@ -0,0 +1,190 @@
+/*
+    Compares the per-row `register_fingerprint` path with `BulkLoader` on a synthetic corpus, then
+    Philips sub-fingerprints inserted with their hash index in place with the same rows inserted
+    before the index is built.
+
+        cargo bench --bench bulk_load
+        SHEZEM_BENCH_SONGS=1000 SHEZEM_BENCH_HASHES=2000 cargo bench --bench bulk_load
+
+    Every song gets the same number of random fingerprints, written to a fresh database file.
+*/
+
+use std::{env, fs, path::PathBuf, time::Instant};
+
+use shezem_rs::{
+    db::{BulkLoader, DbClient, SongData},
+    fingerprint::{Fingerprint, FingerprintData},
+    philips::{SubFingerprint, SubFingerprintData},
+};
+
+fn env_or(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// xorshift, good enough to spread addresses over the whole range
+fn random(state: &mut u64) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state >> 32) as u32
+}
+
+fn corpus(song_id: i32, hashes: usize) -> impl Iterator<Item = FingerprintData> {
+    let mut state = song_id as u64 * 0x9e3779b97f4a7c15 + 1;
+    (0..hashes).map(move |i| FingerprintData {
+        fingerprint: Fingerprint {
+            address: random(&mut state),
+            anchor_address: random(&mut state),
+            anchor_time: i as u32 * 10,
+        },
+        song_id,
+    })
+}
+
+fn sub_fingerprints(song_id: i32, frames: usize) -> impl Iterator<Item = SubFingerprintData> {
+    let mut state = song_id as u64 * 0x9e3779b97f4a7c15 + 1;
+    (0..frames).map(move |frame| SubFingerprintData {
+        sub_fingerprint: SubFingerprint {
+            hash: random(&mut state),
+            frame: frame as u32,
+        },
+        song_id,
+    })
+}
+
+// Inserts every sub-fingerprint row by row, with the hash index kept up to date or built at the end
+fn load_sub_fingerprints(name: &str, songs: usize, frames: usize, index_after: bool) -> f64 {
+    let mut db_client = fresh_database(name);
+    let song_ids = register_songs(&db_client, songs);
+    let start = Instant::now();
+    db_client.set_bulk_load_mode(true).unwrap();
+    let tx = db_client.get_conn().unwrap();
+    if index_after {
+        DbClient::drop_sub_fingerprint_index(&tx).unwrap();
+    }
+    for &song_id in &song_ids {
+        for sub_fingerprint in sub_fingerprints(song_id, frames) {
+            DbClient::register_sub_fingerprint(&tx, &sub_fingerprint).unwrap();
+        }
+    }
+    if index_after {
+        DbClient::build_sub_fingerprint_index(&tx).unwrap();
+    }
+    tx.commit().unwrap();
+    db_client.set_bulk_load_mode(false).unwrap();
+    let elapsed = start.elapsed();
+    drop(db_client);
+    remove_database(name);
+    elapsed.as_secs_f64()
+}
+
+fn database_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("shezem-bench-{}-{}.db3", name, std::process::id()))
+}
+
+fn remove_database(name: &str) {
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{}", database_path(name).display(), suffix));
+    }
+}
+
+fn fresh_database(name: &str) -> DbClient {
+    remove_database(name);
+    DbClient::new(&database_path(name)).unwrap()
+}
+
+fn register_songs(db_client: &DbClient, songs: usize) -> Vec<i32> {
+    (0..songs)
+        .map(|i| {
+            db_client
+                .register_song(&SongData {
+                    title: format!("song{}", i),
+                    path: Some(
+                        PathBuf::from(format!("song{}.mp3", i))
+                            .display()
+                            .to_string(),
+                    ),
+                    ..Default::default()
+                })
+                .unwrap() as i32
+        })
+        .collect()
+}
+
+fn main() {
+    let songs = env_or("SHEZEM_BENCH_SONGS", 10_000);
+    let hashes = env_or("SHEZEM_BENCH_HASHES", 500);
+    let rows = songs * hashes;
+    println!("{} songs x {} hashes = {} rows", songs, hashes, rows);
+
+    let mut db_client = fresh_database("rows");
+    let song_ids = register_songs(&db_client, songs);
+    let start = Instant::now();
+    let mut tx = db_client.get_conn().unwrap();
+    for &song_id in &song_ids {
+        for fingerprint in corpus(song_id, hashes) {
+            DbClient::register_fingerprint(&fingerprint, &mut tx).unwrap();
+        }
+    }
+    tx.commit().unwrap();
+    let per_row = start.elapsed();
+    println!(
+        "register_fingerprint: {:.2?} ({:.0} rows/s)",
+        per_row,
+        rows as f64 / per_row.as_secs_f64()
+    );
+
+    let mut db_client = fresh_database("bulk");
+    let song_ids = register_songs(&db_client, songs);
+    let start = Instant::now();
+    db_client.set_bulk_load_mode(true).unwrap();
+    let tx = db_client.get_conn().unwrap();
+    let mut next_report = rows / 10;
+    let mut loader = BulkLoader::new(&tx).unwrap().with_progress(|staged| {
+        if staged >= next_report {
+            println!("  staged {} rows after {:.2?}", staged, start.elapsed());
+            next_report += rows / 10;
+        }
+    });
+    for &song_id in &song_ids {
+        for fingerprint in corpus(song_id, hashes) {
+            loader.push(fingerprint).unwrap();
+        }
+    }
+    let inserted = loader.finish().unwrap();
+    tx.commit().unwrap();
+    db_client.set_bulk_load_mode(false).unwrap();
+    let bulk = start.elapsed();
+    println!(
+        "BulkLoader: {:.2?} ({:.0} rows/s, {} inserted)",
+        bulk,
+        rows as f64 / bulk.as_secs_f64(),
+        inserted
+    );
+
+    println!(
+        "speedup: {:.1}x",
+        per_row.as_secs_f64() / bulk.as_secs_f64()
+    );
+
+    drop(db_client);
+    remove_database("rows");
+    remove_database("bulk");
+
+    let maintained = load_sub_fingerprints("philips", songs, hashes, false);
+    let built = load_sub_fingerprints("philips", songs, hashes, true);
+    println!(
+        "sub-fingerprints, index kept up to date: {:.2}s ({:.0} rows/s)",
+        maintained,
+        rows as f64 / maintained
+    );
+    println!(
+        "sub-fingerprints, index built after the load: {:.2}s ({:.0} rows/s)",
+        built,
+        rows as f64 / built
+    );
+    println!("speedup: {:.1}x", maintained / built);
+}
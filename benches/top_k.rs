@@ -0,0 +1,165 @@
+/*
+    Compares the exact search with stop-hash filtering (`max_postings`) and early termination
+    (`max_candidates`) on a synthetic catalogue, for speed and recall.
+
+        cargo bench --bench top_k
+        SHEZEM_BENCH_SONGS=2000 SHEZEM_BENCH_QUERIES=200 cargo bench --bench top_k
+
+    Songs are random peaks, with a few held chords shared by the whole catalogue standing in for the
+    low-entropy passages of real music. Queries are excerpts of random songs with a part of their
+    peaks lost and random ones added. Recall is the share of queries whose first result is the song
+    they were taken from.
+*/
+
+use std::{env, time::Instant};
+
+use shezem_rs::{
+    db::SongData,
+    fingerprint::{Fingerprint, generate_fingerprint},
+    memory::MemoryStore,
+    ranking::{self, RankingOptions},
+    spectrogram::{Peak, TimeBase},
+    store::FingerprintStore,
+};
+
+const TIME_BASE: TimeBase = TimeBase {
+    hop_size: 512,
+    sample_rate: 11025,
+};
+const CHORDS: [[u32; 2]; 4] = [[40, 120], [64, 200], [90, 180], [120, 360]];
+
+fn env_or(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// xorshift, good enough to spread frequencies over the whole range
+fn random(state: &mut u64) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state >> 32) as u32
+}
+
+fn peak(frame: u32, freq: u32) -> Peak {
+    Peak {
+        frame,
+        freq,
+        magnitude: 1.0,
+    }
+}
+
+// Two random peaks per frame, and 20 frames of a held chord every 100 frames
+fn song(song_id: i32, frames: u32) -> Vec<Peak> {
+    let mut state = song_id as u64 * 0x9e3779b97f4a7c15 + 1;
+    let mut peaks = Vec::new();
+    for frame in 0..frames {
+        if frame % 100 < 20 {
+            let chord = CHORDS[(frame / 100 + song_id as u32) as usize % CHORDS.len()];
+            peaks.extend(chord.map(|freq| peak(frame, freq)));
+        } else {
+            peaks.extend((0..2).map(|_| peak(frame, random(&mut state) % 512)));
+        }
+    }
+    peaks
+}
+
+// `frames` frames of the song from `start`, with about a fifth of the peaks replaced by random ones
+fn excerpt(song: &[Peak], start: u32, frames: u32, state: &mut u64) -> Vec<Peak> {
+    song.iter()
+        .filter(|p| (start..start + frames).contains(&p.frame))
+        .map(|p| {
+            let freq = if random(state).is_multiple_of(5) {
+                random(state) % 512
+            } else {
+                p.freq
+            };
+            peak(p.frame - start, freq)
+        })
+        .collect()
+}
+
+fn main() {
+    let songs = env_or("SHEZEM_BENCH_SONGS", 500);
+    let frames = env_or("SHEZEM_BENCH_FRAMES", 600) as u32;
+    let queries = env_or("SHEZEM_BENCH_QUERIES", 50);
+    let query_frames = env_or("SHEZEM_BENCH_QUERY_FRAMES", 150) as u32;
+    println!(
+        "{} songs x {} frames, {} queries of {} frames",
+        songs, frames, queries, query_frames
+    );
+
+    let start = Instant::now();
+    let mut store = MemoryStore::new();
+    let mut catalogue = Vec::new();
+    for i in 0..songs {
+        let song_id = store
+            .insert_song(&SongData {
+                title: format!("song{}", i),
+                ..Default::default()
+            })
+            .unwrap();
+        let peaks = song(song_id, frames);
+        store
+            .insert_fingerprints(song_id, &generate_fingerprint(peaks.clone(), TIME_BASE))
+            .unwrap();
+        catalogue.push((song_id, peaks));
+    }
+    println!("indexed in {:.2?}", start.elapsed());
+
+    let mut state = 0x2545f4914f6cdd1d;
+    let queries: Vec<(String, Vec<Fingerprint>)> = (0..queries)
+        .map(|_| {
+            let (song_id, peaks) = &catalogue[random(&mut state) as usize % catalogue.len()];
+            let offset = random(&mut state) % (frames - query_frames).max(1);
+            let peaks = excerpt(peaks, offset, query_frames, &mut state);
+            (
+                format!("song{}", song_id - 1),
+                generate_fingerprint(peaks, TIME_BASE),
+            )
+        })
+        .collect();
+
+    let busiest = env_or("SHEZEM_BENCH_MAX_POSTINGS", songs / 10);
+    let best = env_or("SHEZEM_BENCH_MAX_CANDIDATES", 20);
+    let configurations = [
+        ("exact", None, None),
+        ("max_postings", Some(busiest), None),
+        ("max_candidates", None, Some(best)),
+        ("both", Some(busiest), Some(best)),
+    ];
+    println!("max_postings = {}, max_candidates = {}", busiest, best);
+    println!(
+        "{:<16} {:>12} {:>10} {:>10}",
+        "search", "ms/query", "recall@1", "speedup"
+    );
+
+    let mut exact_time = None;
+    for (name, max_postings, max_candidates) in configurations {
+        let options = RankingOptions {
+            rank: 1,
+            max_postings,
+            max_candidates,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut found = 0;
+        for (title, fingerprints) in &queries {
+            let result = ranking::search(&store, fingerprints.clone(), &options).unwrap();
+            if result.first().is_some_and(|r| &r.data.title == title) {
+                found += 1;
+            }
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        let exact = *exact_time.get_or_insert(elapsed);
+        println!(
+            "{:<16} {:>12.2} {:>10.3} {:>9.1}x",
+            name,
+            elapsed * 1000.0 / queries.len() as f64,
+            found as f64 / queries.len() as f64,
+            exact / elapsed
+        );
+    }
+}
@@ -0,0 +1,413 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    db::{BitErrorRanking, SongData},
+    error::Result,
+    fingerprint::FingerprintMode,
+    format::{FingerprintFile, Fingerprints},
+    philips,
+    ranking::{self, RankingData, RankingOptions},
+    store::FingerprintStore,
+};
+
+/*
+    Searching many queries in one process. Decoding and fingerprinting take most of the time of a
+    query and only need the file, so they run on `threads` workers. The lookups go through the one
+    store in the order of the queries, a SQLite connection can't be shared between threads.
+*/
+
+#[derive(Debug, Serialize)]
+pub struct PhilipsResult {
+    #[serde(flatten)]
+    pub ranking: BitErrorRanking,
+    // Seconds into the song at which the query starts
+    pub offset: f32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum BatchRanking {
+    Landmark(Vec<RankingData>),
+    Philips(Vec<PhilipsResult>),
+}
+
+pub struct BatchResult {
+    pub query: PathBuf,
+    // A query that fails doesn't stop the batch
+    pub ranking: Result<BatchRanking>,
+    pub fingerprint_time: Duration,
+    pub search_time: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchFormat {
+    // One row per result, or one row without a song when there is none
+    Csv,
+    // One object per query
+    JsonLines,
+}
+
+type Fingerprinted = (Result<FingerprintFile>, Duration);
+
+/*
+    Calls `on_result` with the result of every query, in the order of `query_files`. `fingerprint`
+    runs on the workers, the search follows the algorithm and mode of the file it returns.
+*/
+pub fn search_many<S: FingerprintStore + ?Sized>(
+    store: &S,
+    query_files: &[PathBuf],
+    fingerprint: impl Fn(&PathBuf) -> Result<FingerprintFile> + Sync,
+    ranking_options: &RankingOptions,
+    threads: usize,
+    mut on_result: impl FnMut(BatchResult) -> Result<()>,
+) -> Result<()> {
+    let threads = threads.max(1);
+    // Every job comes with its own channel for the result, so results are taken in query order
+    let (jobs, job_receiver) =
+        mpsc::sync_channel::<(&PathBuf, mpsc::SyncSender<Fingerprinted>)>(threads);
+    let job_receiver = Mutex::new(job_receiver);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let job_receiver = &job_receiver;
+            let fingerprint = &fingerprint;
+            scope.spawn(move || {
+                loop {
+                    // The lock is held only while waiting for the next job
+                    let job = job_receiver.lock().unwrap().recv();
+                    let Ok((query, result)) = job else {
+                        break;
+                    };
+                    let start = Instant::now();
+                    let file = fingerprint(query);
+                    // The receiver is gone when the batch stopped early
+                    let _ = result.send((file, start.elapsed()));
+                }
+            });
+        }
+
+        /*
+            At most `threads` queries are fingerprinted ahead of the one being searched, so a slow
+            query holds back the others instead of piling their fingerprints up in memory. Returning
+            early drops the jobs and the pending results, which stops the workers.
+        */
+        let jobs = jobs;
+        let mut pending = VecDeque::new();
+        let mut queries = query_files.iter();
+        loop {
+            while pending.len() <= threads
+                && let Some(query) = queries.next()
+            {
+                let (sender, receiver) = mpsc::sync_channel(1);
+                jobs.send((query, sender))
+                    .expect("workers run until the jobs are dropped");
+                pending.push_back((query, receiver));
+            }
+            let Some((query, receiver)) = pending.pop_front() else {
+                break;
+            };
+            let (file, fingerprint_time) =
+                receiver.recv().expect("workers answer every job they take");
+            let start = Instant::now();
+            let ranking = file.and_then(|file| search_file(store, file, ranking_options));
+            on_result(BatchResult {
+                query: query.clone(),
+                ranking,
+                fingerprint_time,
+                search_time: start.elapsed(),
+            })?;
+        }
+        Ok(())
+    })
+}
+
+fn search_file<S: FingerprintStore + ?Sized>(
+    store: &S,
+    file: FingerprintFile,
+    ranking_options: &RankingOptions,
+) -> Result<BatchRanking> {
+    match file.fingerprints {
+        Fingerprints::Landmark(fingerprints) => {
+            Ok(BatchRanking::Landmark(match file.config.mode {
+                FingerprintMode::Landmark => ranking::search(store, fingerprints, ranking_options)?,
+                FingerprintMode::Invariant => {
+                    ranking::search_invariant(store, fingerprints, ranking_options)?
+                }
+            }))
+        }
+        Fingerprints::Philips(sub_fingerprints) => {
+            ranking_options.check_landmark_only("Philips")?;
+            let frame_seconds = philips::HOP_SIZE as f32 / file.config.sample_rate as f32;
+            let ranking = store.search_sub_fingerprints(
+                sub_fingerprints,
+                ranking_options.rank,
+                ranking_options.min_confidence,
+            )?;
+            Ok(BatchRanking::Philips(
+                ranking
+                    .into_iter()
+                    .map(|ranking| PhilipsResult {
+                        offset: ranking.frame_offset as f32 * frame_seconds,
+                        ranking,
+                    })
+                    .collect(),
+            ))
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Quotes a CSV field when it holds a separator, a quote or a line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn write_header(out: &mut dyn Write, format: BatchFormat) -> io::Result<()> {
+    match format {
+        BatchFormat::Csv => writeln!(
+            out,
+            "query,rank,title,artist,path,score,bit_error_rate,confidence,offset,fingerprint_ms,search_ms,error"
+        ),
+        BatchFormat::JsonLines => Ok(()),
+    }
+}
+
+#[derive(Serialize)]
+struct JsonResult<'a> {
+    query: &'a PathBuf,
+    results: &'a BatchRanking,
+    fingerprint_ms: f64,
+    search_ms: f64,
+    error: Option<String>,
+}
+
+// The song and the columns of a CSV row, landmark results have a score and Philips ones a bit error rate
+fn csv_rows(ranking: &BatchRanking) -> Vec<(&SongData, String, String, f32, f32)> {
+    match ranking {
+        BatchRanking::Landmark(ranking) => ranking
+            .iter()
+            .map(|data| {
+                let score = data.score.to_string();
+                (
+                    &data.data,
+                    score,
+                    String::new(),
+                    data.confidence,
+                    data.position.offset,
+                )
+            })
+            .collect(),
+        BatchRanking::Philips(ranking) => ranking
+            .iter()
+            .map(|result| {
+                let data = &result.ranking;
+                let bit_error_rate = format!("{:.3}", data.bit_error_rate);
+                (
+                    &data.data,
+                    String::new(),
+                    bit_error_rate,
+                    data.confidence,
+                    result.offset,
+                )
+            })
+            .collect(),
+    }
+}
+
+pub fn write_result(
+    out: &mut dyn Write,
+    format: BatchFormat,
+    result: &BatchResult,
+) -> io::Result<()> {
+    let empty = BatchRanking::Landmark(Vec::new());
+    let (ranking, error) = match &result.ranking {
+        Ok(ranking) => (ranking, None),
+        Err(err) => (&empty, Some(err.to_string())),
+    };
+    let fingerprint_ms = millis(result.fingerprint_time);
+    let search_ms = millis(result.search_time);
+
+    if format == BatchFormat::JsonLines {
+        let line = JsonResult {
+            query: &result.query,
+            results: ranking,
+            fingerprint_ms,
+            search_ms,
+            error,
+        };
+        serde_json::to_writer(&mut *out, &line)?;
+        return writeln!(out);
+    }
+
+    let query = csv_field(&result.query.to_string_lossy());
+    let rows = csv_rows(ranking);
+    if rows.is_empty() {
+        return writeln!(
+            out,
+            "{},,,,,,,,,{:.1},{:.1},{}",
+            query,
+            fingerprint_ms,
+            search_ms,
+            csv_field(error.as_deref().unwrap_or_default())
+        );
+    }
+    for (index, (data, score, bit_error_rate, confidence, offset)) in rows.into_iter().enumerate() {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{:.3},{:.3},{:.1},{:.1},",
+            query,
+            index + 1,
+            csv_field(&data.title),
+            csv_field(data.artist.as_deref().unwrap_or_default()),
+            csv_field(data.path.as_deref().unwrap_or_default()),
+            score,
+            bit_error_rate,
+            confidence,
+            offset,
+            fingerprint_ms,
+            search_ms
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        IndexOptions,
+        db::SongData,
+        error::Error,
+        fingerprint::{Algorithm, generate_fingerprint},
+        format::FingerprintConfig,
+        memory::MemoryStore,
+        test_utils::{TIME_BASE, excerpt, peaks},
+    };
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a, b"), "\"a, b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_failed_query_keeps_the_batch_going() {
+        let store = MemoryStore::new();
+        let queries = [
+            PathBuf::from("missing-1.mp3"),
+            PathBuf::from("missing-2.mp3"),
+        ];
+        let options = RankingOptions {
+            rank: 1,
+            scorer: &ranking::LisScorer,
+            min_confidence: 0.0,
+            ..Default::default()
+        };
+        let mut results = Vec::new();
+        search_many(
+            &store,
+            &queries,
+            |query| crate::compute_fingerprints(query, IndexOptions::default()),
+            &options,
+            2,
+            |result| {
+                results.push(result);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            results.iter().map(|r| &r.query).collect::<Vec<_>>(),
+            queries.iter().collect::<Vec<_>>()
+        );
+        assert!(
+            results
+                .iter()
+                .all(|r| matches!(r.ranking, Err(Error::Io { .. })))
+        );
+
+        let mut csv = Vec::new();
+        write_result(&mut csv, BatchFormat::Csv, &results[0]).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("missing-1.mp3,,,,,,,,,"));
+        assert!(csv.contains("missing-1.mp3:"));
+    }
+
+    #[test]
+    fn test_results_come_in_query_order() {
+        let mut store = MemoryStore::new();
+        for seed in 1..=3 {
+            let song_id = store
+                .insert_song(&SongData {
+                    title: format!("song{}", seed),
+                    ..Default::default()
+                })
+                .unwrap();
+            let fingerprints = generate_fingerprint(peaks(seed, 0..300), TIME_BASE);
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+        // Each query is named after the song it was taken from, the first one is the longest to search
+        let queries = [
+            PathBuf::from("3.mp3"),
+            PathBuf::from("1.mp3"),
+            PathBuf::from("2.mp3"),
+        ];
+        let fingerprint = |query: &PathBuf| {
+            let seed = query
+                .file_stem()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            let frames = if seed == 3 { 0..250 } else { 100..150 };
+            Ok(FingerprintFile::new(
+                FingerprintConfig::new(Algorithm::Landmark, FingerprintMode::Landmark, 11025),
+                Fingerprints::Landmark(generate_fingerprint(excerpt(seed, frames, 0), TIME_BASE)),
+            ))
+        };
+        let options = RankingOptions {
+            rank: 1,
+            scorer: &ranking::LisScorer,
+            min_confidence: 0.0,
+            ..Default::default()
+        };
+        let mut results = Vec::new();
+        search_many(&store, &queries, fingerprint, &options, 2, |result| {
+            results.push(result);
+            Ok(())
+        })
+        .unwrap();
+
+        let titles = results
+            .iter()
+            .map(|result| match &result.ranking {
+                Ok(BatchRanking::Landmark(ranking)) => ranking[0].data.title.clone(),
+                other => panic!("unexpected ranking {:?}", other.as_ref().err()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(titles, ["song3", "song1", "song2"]);
+
+        let mut csv = Vec::new();
+        write_result(&mut csv, BatchFormat::Csv, &results[1]).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("1.mp3,1,song1,,,"));
+    }
+}
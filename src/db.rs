@@ -1,84 +1,450 @@
-use std::{cmp::max, collections::HashMap, path::PathBuf};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
-use anyhow::Result;
-use rusqlite::{Connection, Transaction, params};
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    NEIGHBORHOOD_SIZE,
-    fingerprint::{Fingerprint, FingerprintData},
-    utils::longest_increasing_subsequence,
+    error::{Error, Result},
+    fingerprint::{Algorithm, Fingerprint, FingerprintData, FingerprintMode},
+    migrations::migrate,
+    philips::{self, SubFingerprint, SubFingerprintData, bit_error_rate},
+    ranking::{self, OFFSET_BIN, RankingData, RankingOptions},
+    store::{FingerprintStore, unique_sorted},
 };
 
-#[derive(Debug)]
+const FINGERPRINT_MODE_KEY: &str = "fingerprint_mode";
+const ALGORITHM_KEY: &str = "algorithm";
+const DENSITY_KEY: &str = "density";
+const SAMPLE_RATE_KEY: &str = "sample_rate";
+
+// Number of best voted alignments per song that get verified by bit error rate
+const PHILIPS_CANDIDATES_PER_SONG: usize = 3;
+// Fingerprints shared by more songs are too common to tell duplicates apart, like stop words
+const MAX_DUPLICATE_GROUP: usize = 64;
+// Couples of songs voted for before the ones that can't be duplicates any more are dropped
+const DUPLICATE_PAIRS_BEFORE_PRUNING: usize = 1 << 20;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SongData {
-    // Only title for now
     pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+    // International Standard Recording Code, identifies the recording across releases
+    pub isrc: Option<String>,
+    pub duration_ms: Option<u32>,
+    // Path of the indexed file at index time
+    pub path: Option<String>,
+    pub file_size: Option<u64>,
+    pub content_hash: Option<String>,
+    // Modification time of the file in nanoseconds since the UNIX epoch
+    pub modified_at: Option<i64>,
 }
 
+impl SongData {
+    // "Artist - Title" when the artist is known, the title alone otherwise
+    pub fn display_name(&self) -> String {
+        match &self.artist {
+            Some(artist) => format!("{} - {}", artist, self.title),
+            None => self.title.clone(),
+        }
+    }
+
+    fn from_row(row: &rusqlite::Row, offset: usize) -> rusqlite::Result<Self> {
+        Ok(SongData {
+            title: row.get(offset)?,
+            artist: row.get(offset + 1)?,
+            album: row.get(offset + 2)?,
+            track_number: row.get(offset + 3)?,
+            isrc: row.get(offset + 4)?,
+            duration_ms: row.get(offset + 5)?,
+            path: row.get(offset + 6)?,
+            file_size: row.get(offset + 7)?,
+            content_hash: row.get(offset + 8)?,
+            modified_at: row.get(offset + 9)?,
+        })
+    }
+}
+
+// Columns read by `SongData::from_row`, in order
+const SONG_COLUMNS: &str =
+    "title, artist, album, trackNumber, isrc, durationMs, path, fileSize, contentHash, modifiedAt";
+
 #[derive(Debug)]
-pub struct RankingData {
+pub struct SongStats {
+    pub song_id: i32,
+    pub title: String,
+    // Fingerprints of the song, sub-fingerprints in Philips indexes
+    pub hashes: usize,
+    // Span between the first and the last anchor, the decoded duration in Philips indexes
+    pub duration_ms: u32,
+}
+
+impl SongStats {
+    pub fn hashes_per_second(&self) -> f32 {
+        if self.duration_ms == 0 {
+            return 0.0;
+        }
+        self.hashes as f32 * 1000.0 / self.duration_ms as f32
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BitErrorRanking {
+    pub song_id: i32,
     pub data: SongData,
-    pub score: i32,
+    pub bit_error_rate: f32,
+    // 1 for an exact copy down to 0 at the bit error rate threshold
+    pub confidence: f32,
+    // Reference frame minus query frame of the best alignment
+    pub frame_offset: i64,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct Couples {
-    pub anchor_address: u32,
-    pub anchor_time: u32,
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicatePair {
+    // The song with the fewest fingerprints, found in the other one
     pub song_id: i32,
+    pub other_id: i32,
+    // Share of the fingerprints of `song_id` found in the other song at a constant offset
+    pub overlap: f32,
+    // Time of the other song at which `song_id` starts, in seconds
+    pub offset: f32,
+}
+
+#[derive(Debug)]
+pub struct DuplicateGroup {
+    pub songs: Vec<(i32, SongData)>,
+    pub pairs: Vec<DuplicatePair>,
 }
 
 pub struct DbClient {
     conn: Connection,
+    // Journal mode and synchronous setting from before `set_bulk_load_mode(true)`
+    saved_modes: Option<(String, i64)>,
 }
 
 impl DbClient {
-    pub fn new(path: &PathBuf) -> Self {
-        let conn = Connection::open(path).unwrap();
-        let client = DbClient { conn };
-        client.create_tables().unwrap();
-        client
+    pub fn new(path: &PathBuf) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        // Room for every size of lookup statement next to the other cached ones
+        conn.set_prepared_statement_cache_capacity(64);
+        migrate(&mut conn)?;
+        Ok(DbClient {
+            conn,
+            saved_modes: None,
+        })
     }
-    pub fn get_conn<'a>(&'a mut self) -> Transaction<'a> {
-        self.conn.transaction().unwrap()
+    pub fn get_conn<'a>(&'a mut self) -> Result<Transaction<'a>> {
+        Ok(self.conn.transaction()?)
     }
-    pub fn create_tables(&self) -> rusqlite::Result<()> {
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS songs (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                title TEXT NOT NULL
-            )",
-            [],
-        )?;
 
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS fingerprints (
-                address INTEGER NOT NULL,
-                anchorAddress INTEGER NOT NULL,
-                anchorTime INTEGER NOT NULL,
-                songID INTEGER NOT NULL,
-                PRIMARY KEY (address, anchorAddress, anchorTime, songID)
-            )",
-            [],
-        )?;
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT value FROM settings WHERE key = ?")?;
+        let value = stmt.query_row([key], |row| row.get(0)).optional()?;
+        Ok(value)
+    }
 
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        Self::write_setting(&self.conn, key, value)
+    }
+
+    fn write_setting(conn: &Connection, key: &str, value: &str) -> Result<()> {
+        let mut stmt =
+            conn.prepare_cached("INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)")?;
+        stmt.execute([key, value])?;
         Ok(())
     }
 
+    fn write_density(conn: &Connection, density: Option<usize>) -> Result<()> {
+        match density {
+            Some(density) => Self::write_setting(conn, DENSITY_KEY, &density.to_string()),
+            None => {
+                conn.execute("DELETE FROM settings WHERE key = ?", [DENSITY_KEY])?;
+                Ok(())
+            }
+        }
+    }
+
+    /*
+        Records the options the fingerprints were made with. Indexing passes the transaction that
+        wrote the fingerprints, so they can't be committed without their options.
+    */
+    pub fn save_index_options(
+        conn: &Connection,
+        algorithm: Algorithm,
+        mode: FingerprintMode,
+        density: Option<usize>,
+        sample_rate: Option<usize>,
+    ) -> Result<()> {
+        Self::write_setting(conn, ALGORITHM_KEY, algorithm.as_str())?;
+        Self::write_setting(conn, FINGERPRINT_MODE_KEY, mode.as_str())?;
+        Self::write_density(conn, density)?;
+        if let Some(sample_rate) = sample_rate {
+            Self::write_setting(conn, SAMPLE_RATE_KEY, &sample_rate.to_string())?;
+        }
+        Ok(())
+    }
+
+    pub fn fingerprint_mode(&self) -> Result<FingerprintMode> {
+        Ok(self
+            .get_setting(FINGERPRINT_MODE_KEY)?
+            .and_then(|value| FingerprintMode::parse(&value))
+            .unwrap_or_default())
+    }
+
+    pub fn set_fingerprint_mode(&self, mode: FingerprintMode) -> Result<()> {
+        self.set_setting(FINGERPRINT_MODE_KEY, mode.as_str())
+    }
+
+    pub fn algorithm(&self) -> Result<Algorithm> {
+        Ok(self
+            .get_setting(ALGORITHM_KEY)?
+            .and_then(|value| Algorithm::parse(&value))
+            .unwrap_or_default())
+    }
+
+    pub fn set_algorithm(&self, algorithm: Algorithm) -> Result<()> {
+        self.set_setting(ALGORITHM_KEY, algorithm.as_str())
+    }
+
     pub fn register_song(&self, song_data: &SongData) -> Result<i64> {
-        let mut stmt = self
-            .conn
-            .prepare_cached("INSERT INTO songs (title) VALUES (?)")?;
-        let result = stmt.execute([&song_data.title])?;
+        Self::insert_song(&self.conn, song_data)
+    }
+
+    // Also used with a transaction, which derefs to a connection
+    pub(crate) fn insert_song(conn: &Connection, song_data: &SongData) -> Result<i64> {
+        let mut stmt = conn.prepare_cached(&format!(
+            "INSERT INTO songs ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            SONG_COLUMNS
+        ))?;
+        let result = stmt.execute(params![
+            &song_data.title,
+            &song_data.artist,
+            &song_data.album,
+            &song_data.track_number,
+            &song_data.isrc,
+            &song_data.duration_ms,
+            &song_data.path,
+            &song_data.file_size,
+            &song_data.content_hash,
+            &song_data.modified_at,
+        ])?;
 
         if result == 0 {
             return Err(rusqlite::Error::StatementChangedRows(0).into());
         }
 
-        let song_id = self.conn.last_insert_rowid();
+        let song_id = conn.last_insert_rowid();
         Ok(song_id)
     }
 
+    pub fn update_song(&self, song_id: i32, song_data: &SongData) -> Result<()> {
+        Self::write_song(&self.conn, song_id, song_data)
+    }
+
+    pub(crate) fn write_song(conn: &Connection, song_id: i32, song_data: &SongData) -> Result<()> {
+        let mut stmt = conn.prepare_cached(
+            "UPDATE songs SET title = ?, artist = ?, album = ?, trackNumber = ?, isrc = ?,
+            durationMs = ?, path = ?, fileSize = ?, contentHash = ?, modifiedAt = ? WHERE id = ?",
+        )?;
+        let result = stmt.execute(params![
+            &song_data.title,
+            &song_data.artist,
+            &song_data.album,
+            &song_data.track_number,
+            &song_data.isrc,
+            &song_data.duration_ms,
+            &song_data.path,
+            &song_data.file_size,
+            &song_data.content_hash,
+            &song_data.modified_at,
+            song_id,
+        ])?;
+
+        if result == 0 {
+            return Err(rusqlite::Error::QueryReturnedNoRows.into());
+        }
+        Ok(())
+    }
+
+    pub fn find_song_by_path(&self, path: &str) -> Result<Option<(i32, SongData)>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT id, {} FROM songs WHERE path = ? ORDER BY id LIMIT 1",
+            SONG_COLUMNS
+        ))?;
+        let song = stmt
+            .query_row([path], |row| Ok((row.get(0)?, SongData::from_row(row, 1)?)))
+            .optional()?;
+        Ok(song)
+    }
+
+    // Songs indexed before paths were recorded
+    pub fn legacy_songs(&self) -> Result<Vec<(i32, SongData)>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, {} FROM songs WHERE path IS NULL ORDER BY id",
+            SONG_COLUMNS
+        ))?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, SongData::from_row(row, 1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    pub fn song_paths(&self) -> Result<Vec<(i32, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, path FROM songs WHERE path IS NOT NULL")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /*
+        Songs ordered by id. The filter is matched case-insensitively against the title, artist,
+        album and path, `%` and `_` in it are taken literally.
+    */
+    pub fn list_songs(
+        &self,
+        filter: Option<&str>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<(i32, SongData)>> {
+        let pattern = filter.map(|filter| {
+            let escaped = filter
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT id, {} FROM songs
+            WHERE ?1 IS NULL OR title LIKE ?1 ESCAPE '\\' OR artist LIKE ?1 ESCAPE '\\'
+                OR album LIKE ?1 ESCAPE '\\' OR path LIKE ?1 ESCAPE '\\'
+            ORDER BY id LIMIT ?2 OFFSET ?3",
+            SONG_COLUMNS
+        ))?;
+        let rows = stmt
+            .query_map(params![pattern, limit as i64, offset as i64], |row| {
+                Ok((row.get(0)?, SongData::from_row(row, 1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    pub fn songs(&self) -> Result<Vec<(i32, SongData)>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, {} FROM songs ORDER BY id",
+            SONG_COLUMNS
+        ))?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, SongData::from_row(row, 1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    // Streams every landmark fingerprint in primary key order, so grouped by address
+    pub fn for_each_fingerprint(
+        &self,
+        mut f: impl FnMut(FingerprintData) -> Result<()>,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare(
+            "SELECT address, anchorAddress, anchorTime, songID FROM fingerprints
+            ORDER BY address, anchorAddress, anchorTime, songID",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            f(FingerprintData {
+                fingerprint: Fingerprint {
+                    address: row.get(0)?,
+                    anchor_address: row.get(1)?,
+                    anchor_time: row.get(2)?,
+                },
+                song_id: row.get(3)?,
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn song_count(&self) -> Result<usize> {
+        Ok(self
+            .conn
+            .query_row("SELECT COUNT(*) FROM songs", [], |row| row.get(0))?)
+    }
+
+    /*
+        Drops the fingerprints of the songs from every algorithm's table, the song rows stay.
+        The fingerprints table has no index on songID, so songs are deleted in batches to scan it once.
+    */
+    pub(crate) fn clear_fingerprints(conn: &Connection, song_ids: &[i32]) -> rusqlite::Result<()> {
+        // Stay well below SQLite's limit on bound parameters
+        for chunk in song_ids.chunks(500) {
+            let placeholders = vec!["?"; chunk.len()].join(",");
+            for table in ["fingerprints", "sub_fingerprints"] {
+                conn.execute(
+                    &format!("DELETE FROM {} WHERE songID IN ({})", table, placeholders),
+                    rusqlite::params_from_iter(chunk),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete_songs(conn: &Connection, song_ids: &[i32]) -> rusqlite::Result<()> {
+        Self::clear_fingerprints(conn, song_ids)?;
+        for song_id in song_ids {
+            conn.execute("DELETE FROM songs WHERE id = ?", [song_id])?;
+        }
+        Ok(())
+    }
+
+    /*
+        Removes the songs and their fingerprints in one transaction, so that nothing is removed unless
+        every song exists and the fingerprints table is scanned once. Returns the removed songs.
+    */
+    pub fn remove_songs(&mut self, song_ids: &[i32]) -> Result<Vec<(i32, SongData)>> {
+        let tx = self.conn.transaction()?;
+        let mut songs: Vec<(i32, SongData)> = Vec::new();
+        {
+            let mut stmt =
+                tx.prepare_cached(&format!("SELECT {} FROM songs WHERE id = ?", SONG_COLUMNS))?;
+            for &song_id in song_ids {
+                if songs.iter().any(|&(removed, _)| removed == song_id) {
+                    continue;
+                }
+                let song = stmt
+                    .query_row([song_id], |row| SongData::from_row(row, 0))
+                    .optional()?
+                    .ok_or(Error::SongNotFound(song_id))?;
+                songs.push((song_id, song));
+            }
+        }
+        let song_ids = songs
+            .iter()
+            .map(|&(song_id, _)| song_id)
+            .collect::<Vec<_>>();
+        Self::delete_songs(&tx, &song_ids)?;
+        tx.commit()?;
+        Ok(songs)
+    }
+
+    // The duration is only known once the file is decoded, after the song was registered
+    pub fn set_song_duration(
+        conn: &Connection,
+        song_id: i32,
+        duration_ms: u32,
+    ) -> rusqlite::Result<()> {
+        let mut stmt = conn.prepare_cached("UPDATE songs SET durationMs = ? WHERE id = ?")?;
+        stmt.execute(params![duration_ms, song_id])?;
+        Ok(())
+    }
+
     pub fn register_fingerprint<'a>(
         fingerprint_data: &FingerprintData,
         tx: &mut Transaction<'a>,
@@ -96,125 +462,911 @@ impl DbClient {
         Ok(())
     }
 
+    /*
+        WAL journaling and no fsync while a large load runs, `false` puts back the journal mode and
+        durability from before. A crash of the process still rolls the load back, only an OS crash
+        or power loss during the load can corrupt the file. The journal mode can't change inside a
+        transaction, so this has to be called before it starts and after it ends.
+    */
+    pub fn set_bulk_load_mode(&mut self, enabled: bool) -> Result<()> {
+        if enabled {
+            if self.saved_modes.is_none() {
+                self.saved_modes = Some((
+                    self.conn
+                        .pragma_query_value(None, "journal_mode", |row| row.get(0))?,
+                    self.conn
+                        .pragma_query_value(None, "synchronous", |row| row.get(0))?,
+                ));
+            }
+            self.conn
+                .pragma_update_and_check(None, "journal_mode", "WAL", |row| {
+                    row.get::<_, String>(0)
+                })?;
+            self.conn.pragma_update(None, "synchronous", "OFF")?;
+        } else if let Some((journal_mode, synchronous)) = self.saved_modes.take() {
+            self.conn.pragma_update(None, "synchronous", synchronous)?;
+            self.conn
+                .pragma_update_and_check(None, "journal_mode", journal_mode, |row| {
+                    row.get::<_, String>(0)
+                })?;
+        }
+        Ok(())
+    }
+
+    /*
+        The hash index of the sub-fingerprints is dropped while they are loaded and built again once
+        they are all in, which sorts them once instead of inserting every row at a random place of
+        the index. Both run inside the loading transaction, so readers never see the table without it.
+    */
+    pub fn drop_sub_fingerprint_index(conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute("DROP INDEX IF EXISTS sub_fingerprints_hash", [])?;
+        Ok(())
+    }
+
+    pub fn build_sub_fingerprint_index(conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS sub_fingerprints_hash ON sub_fingerprints (hash)",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn density(&self) -> Result<Option<usize>> {
+        Ok(self
+            .get_setting(DENSITY_KEY)?
+            .and_then(|value| value.parse().ok()))
+    }
+
+    pub fn set_density(&self, density: Option<usize>) -> Result<()> {
+        Self::write_density(&self.conn, density)
+    }
+
+    // Sample rate the indexed audio was fingerprinted at, None for indexes that predate it
+    pub fn sample_rate(&self) -> Result<Option<usize>> {
+        Ok(self
+            .get_setting(SAMPLE_RATE_KEY)?
+            .and_then(|value| value.parse().ok()))
+    }
+
+    pub fn set_sample_rate(&self, sample_rate: usize) -> Result<()> {
+        self.set_setting(SAMPLE_RATE_KEY, &sample_rate.to_string())
+    }
+
+    pub fn register_sub_fingerprint(
+        conn: &Connection,
+        sub_fingerprint_data: &SubFingerprintData,
+    ) -> rusqlite::Result<()> {
+        let mut stmt = conn.prepare_cached(
+            "INSERT OR IGNORE INTO sub_fingerprints (hash, frame, songID) VALUES (?, ?, ?)",
+        )?;
+        stmt.execute(params![
+            &sub_fingerprint_data.sub_fingerprint.hash,
+            &sub_fingerprint_data.sub_fingerprint.frame,
+            &sub_fingerprint_data.song_id,
+        ])?;
+
+        Ok(())
+    }
+
     pub fn get_song_data(&self, song_id: i32) -> Result<SongData> {
         let mut stmt = self
             .conn
-            .prepare_cached("SELECT title FROM songs WHERE id = ?")?;
-        let row = stmt.query_row([song_id], |row| Ok(SongData { title: row.get(0)? }))?;
-
-        Ok(row)
+            .prepare_cached(&format!("SELECT {} FROM songs WHERE id = ?", SONG_COLUMNS))?;
+        stmt.query_row([song_id], |row| SongData::from_row(row, 0))
+            .optional()?
+            .ok_or(Error::SongNotFound(song_id))
     }
-    fn get_fingerprint_from_database(
+    fn for_each_sub_fingerprint_match(
         &self,
-        fingerprints: &Vec<Fingerprint>,
-    ) -> Result<Vec<FingerprintData>> {
-        let placeholders: String = std::iter::repeat("?")
-            .take(fingerprints.len())
-            .collect::<Vec<_>>()
-            .join(",");
-
-        let params: Vec<&dyn rusqlite::types::ToSql> = fingerprints
-            .iter()
-            .map(|f| &f.address as &dyn rusqlite::types::ToSql)
-            .collect();
-
-        let mut stmt = self.conn.prepare(&format!(
-                "SELECT address, anchorAddress, anchorTime, songID FROM fingerprints WHERE address IN ({})",
-                placeholders
+        sub_fingerprints: &[SubFingerprint],
+        mut visit: impl FnMut(SubFingerprintData),
+    ) -> Result<()> {
+        let hashes = unique_sorted(sub_fingerprints.iter().map(|s| s.hash));
+        for chunk in lookup_chunks(&hashes) {
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "SELECT hash, frame, songID FROM sub_fingerprints WHERE hash IN ({})",
+                vec!["?"; chunk.len()].join(",")
             ))?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(chunk))?;
+            while let Some(row) = rows.next()? {
+                visit(SubFingerprintData {
+                    sub_fingerprint: SubFingerprint {
+                        hash: row.get(0)?,
+                        frame: row.get(1)?,
+                    },
+                    song_id: row.get(2)?,
+                });
+            }
+        }
+        Ok(())
+    }
 
+    // Same as `stats` for the sub-fingerprints of a Philips index
+    pub fn sub_fingerprint_stats(&self) -> Result<Vec<SongStats>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT songs.id, songs.title, COUNT(*), COALESCE(songs.durationMs, 0)
+            FROM sub_fingerprints JOIN songs ON songs.id = sub_fingerprints.songID
+            GROUP BY songs.id ORDER BY songs.id",
+        )?;
         let rows = stmt
-            .query_map(params.as_slice(), |row| {
-                Ok(FingerprintData {
-                    fingerprint: Fingerprint {
-                        address: row.get(0)?,
-                        anchor_address: row.get(1)?,
-                        anchor_time: row.get(2)?,
-                    },
-                    song_id: row.get(3)?,
+            .query_map([], |row| {
+                Ok(SongStats {
+                    song_id: row.get(0)?,
+                    title: row.get(1)?,
+                    hashes: row.get(2)?,
+                    duration_ms: row.get(3)?,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
-
         Ok(rows)
     }
-    pub fn search(&self, fingerprints: Vec<Fingerprint>, rank: usize) -> Result<Vec<RankingData>> {
-        let sample_duration =
-            fingerprints.last().unwrap().anchor_time - fingerprints.first().unwrap().anchor_time;
 
-        let index_map: HashMap<u32, usize> = fingerprints
-            .iter()
-            .enumerate()
-            .map(|(i, fp)| (fp.address, i))
-            .collect();
+    fn get_sub_fingerprint_range(
+        &self,
+        song_id: i32,
+        first: i64,
+        last: i64,
+    ) -> Result<Vec<(i64, u32)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT frame, hash FROM sub_fingerprints WHERE songID = ? AND frame BETWEEN ? AND ? ORDER BY frame",
+        )?;
+        let rows = stmt
+            .query_map(params![song_id, first, last], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
 
-        let db_result = self.get_fingerprint_from_database(&fingerprints)?;
+    // The landmark search of earlier versions, with every option but `rank` left at its default
+    #[deprecated(note = "use `ranking::search`, which takes `RankingOptions` and any store")]
+    pub fn search(&self, fingerprints: Vec<Fingerprint>, rank: usize) -> Result<Vec<RankingData>> {
+        ranking::search(
+            self,
+            fingerprints,
+            &RankingOptions {
+                rank,
+                ..Default::default()
+            },
+        )
+    }
 
-        // Track fingerprint matches directly by song ID
-        let mut song_fingerprints: HashMap<i32, Vec<Fingerprint>> = HashMap::new();
+    pub fn search_philips(
+        &self,
+        mut sub_fingerprints: Vec<SubFingerprint>,
+        rank: usize,
+        min_confidence: f32,
+    ) -> Result<Vec<BitErrorRanking>> {
+        if sub_fingerprints.is_empty() {
+            return Ok(Vec::new());
+        }
+        sub_fingerprints.sort_unstable_by_key(|s| s.frame);
 
-        // Count matches to identify complete neighborhoods
-        let mut match_counts: HashMap<Couples, usize> = HashMap::new();
+        let mut query_frames: HashMap<u32, Vec<u32>> = HashMap::new();
+        for s in &sub_fingerprints {
+            query_frames.entry(s.hash).or_default().push(s.frame);
+        }
 
-        for row in db_result {
-            let key = Couples {
-                anchor_address: row.fingerprint.anchor_address,
-                anchor_time: row.fingerprint.anchor_time,
-                song_id: row.song_id,
-            };
-            let count = match_counts.entry(key).or_insert(0);
-            *count += 1;
-
-            if *count == NEIGHBORHOOD_SIZE {
-                song_fingerprints
-                    .entry(row.song_id)
-                    .or_insert_with(Vec::new)
-                    .push(row.fingerprint);
+        /*
+            A sub-fingerprint that survived without a single bit error gives us a candidate alignment
+            (song, reference frame - query frame). Only the most voted alignments of every song are then
+            verified by comparing whole blocks of BLOCK_SIZE sub-fingerprints bit by bit. The bit error
+            rate of an alignment is taken over all of its blocks, so a single lucky block doesn't match.
+        */
+        let mut votes: HashMap<(i32, i64), usize> = HashMap::new();
+        self.for_each_sub_fingerprint_match(&sub_fingerprints, |row| {
+            for &query_frame in &query_frames[&row.sub_fingerprint.hash] {
+                let offset = row.sub_fingerprint.frame as i64 - query_frame as i64;
+                *votes.entry((row.song_id, offset)).or_default() += 1;
             }
+        })?;
+
+        let mut candidates: HashMap<i32, Vec<(usize, i64)>> = HashMap::new();
+        for ((song_id, offset), count) in votes {
+            candidates.entry(song_id).or_default().push((count, offset));
         }
 
-        /*
-            After get the fingerprints for each song from database, we need to verify their temporal coherence with the sample.
+        let first_frame = sub_fingerprints.first().unwrap().frame as i64;
+        let last_frame = sub_fingerprints.last().unwrap().frame as i64;
 
-            We'll implement a Longest Increasing Subsequence (LIS) algorithm on each song's fingerprint to ensure the chronological
-            order of peaks matches our sample's pattern.
+        let mut result = Vec::new();
+        for (song_id, mut offsets) in candidates {
+            offsets.sort_unstable_by_key(|&(count, offset)| (Reverse(count), offset));
+            offsets.truncate(PHILIPS_CANDIDATES_PER_SONG);
 
-            To guard against false positives from random matching peaks, we'll employ a sliding window technique. This window,
-            sized to match our sample duration, will move across the LIS results. The maximum number of matching peaks detected
-            within any window position will serve as our relevance score for that song.
-        */
+            let mut best: Option<(f32, i64)> = None;
+            for (_, offset) in offsets {
+                let reference: HashMap<i64, u32> = self
+                    .get_sub_fingerprint_range(song_id, first_frame + offset, last_frame + offset)?
+                    .into_iter()
+                    .collect();
 
-        let mut result = Vec::with_capacity(song_fingerprints.len());
-        for (song_id, mut fingerprints) in song_fingerprints {
-            fingerprints.sort_unstable_by_key(|fp| index_map[&fp.address]);
-            let times = fingerprints
-                .iter()
-                .map(|v| v.anchor_time)
-                .collect::<Box<[u32]>>();
-            let lis = longest_increasing_subsequence(&times);
-
-            let mut l: usize = 0;
-            let mut score = 0;
-            for r in 0..lis.len() {
-                while lis[r] - lis[l] > sample_duration {
-                    l += 1;
+                let (mut query, mut aligned) = (Vec::new(), Vec::new());
+                for block in sub_fingerprints.chunks(philips::BLOCK_SIZE) {
+                    let (block_query, block_aligned): (Vec<u32>, Vec<u32>) = block
+                        .iter()
+                        .filter_map(|s| {
+                            reference
+                                .get(&(s.frame as i64 + offset))
+                                .map(|&hash| (s.hash, hash))
+                        })
+                        .unzip();
+                    // Blocks running off either end of the reference are not reliable
+                    if block_query.len() * 2 < block.len() {
+                        continue;
+                    }
+                    query.extend(block_query);
+                    aligned.extend(block_aligned);
+                }
+                if query.is_empty() {
+                    continue;
+                }
+                let ber = bit_error_rate(&query, &aligned);
+                if best.is_none_or(|(best_ber, _)| ber < best_ber) {
+                    best = Some((ber, offset));
                 }
-                score = max(score, r - l + 1);
             }
 
-            result.push((song_id, score as i32));
+            if let Some((ber, offset)) = best
+                && ber <= philips::BER_THRESHOLD
+            {
+                result.push((song_id, ber, offset));
+            }
         }
 
-        result.sort_unstable_by_key(|a| -a.1);
+        result.retain(|&(_, ber, _)| 1.0 - ber / philips::BER_THRESHOLD >= min_confidence);
+        result.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
         result.truncate(rank);
 
         Ok(result
             .into_iter()
-            .filter_map(|(song_id, score)| {
+            .filter_map(|(song_id, bit_error_rate, frame_offset)| {
                 self.get_song_data(song_id)
                     .ok()
-                    .map(|data| RankingData { data, score })
+                    .map(|data| BitErrorRanking {
+                        song_id,
+                        data,
+                        bit_error_rate,
+                        confidence: 1.0 - bit_error_rate / philips::BER_THRESHOLD,
+                        frame_offset,
+                    })
             })
             .collect::<Vec<_>>())
     }
+
+    /*
+        Cross-matches every song against the index in one pass over the fingerprints table. Rows
+        sharing an address and anchor address are the same neighborhood in two songs, and every such
+        couple votes for the offset between them. Songs sharing at least `min_overlap` of the
+        fingerprints of the shorter one at a constant offset are duplicates, which also finds a track
+        contained in a longer one, and duplicates of duplicates end up in the same group.
+    */
+    pub fn find_duplicates(&self, min_overlap: f32) -> Result<Vec<DuplicateGroup>> {
+        self.find_duplicates_pruned(min_overlap, DUPLICATE_PAIRS_BEFORE_PRUNING)
+    }
+
+    fn find_duplicates_pruned(
+        &self,
+        min_overlap: f32,
+        mut prune_at: usize,
+    ) -> Result<Vec<DuplicateGroup>> {
+        // Invariant fingerprints match across tempo changes, where the offset between songs drifts
+        if self.algorithm()? != Algorithm::Landmark
+            || self.fingerprint_mode()? != FingerprintMode::Landmark
+        {
+            return Err(Error::Unsupported(
+                "duplicates can only be found in landmark indexes built without --invariant"
+                    .to_string(),
+            ));
+        }
+
+        let counts = self
+            .stats()?
+            .into_iter()
+            .map(|stats| (stats.song_id, stats.hashes))
+            .collect::<HashMap<_, _>>();
+        let total = counts.values().sum::<usize>();
+        // Offset votes of every couple of songs (lower id, higher id), binned by OFFSET_BIN
+        let mut votes: HashMap<(i32, i32), HashMap<i64, usize>> = HashMap::new();
+        let vote = |votes: &mut HashMap<(i32, i32), HashMap<i64, usize>>, group: &[(i32, u32)]| {
+            if group.len() > MAX_DUPLICATE_GROUP {
+                return;
+            }
+            for (i, &(song_id, time)) in group.iter().enumerate() {
+                for &(other_id, other_time) in &group[i + 1..] {
+                    if song_id == other_id {
+                        continue;
+                    }
+                    let (key, offset) = if song_id < other_id {
+                        ((song_id, other_id), other_time as i64 - time as i64)
+                    } else {
+                        ((other_id, song_id), time as i64 - other_time as i64)
+                    };
+                    let bin = offset.div_euclid(OFFSET_BIN as i64);
+                    *votes.entry(key).or_default().entry(bin).or_default() += 1;
+                }
+            }
+        };
+
+        /*
+            Most couples only share a few fingerprints by chance, and there can be as many as songs
+            squared. Rows come in address order, which spreads the fingerprints of every song evenly
+            over the scan, so after a share of the rows a duplicate has about that share of its votes.
+            When the couples pile up, the ones with less than half of that share of the votes they
+            need to reach `min_overlap` are dropped.
+        */
+        let prune = |votes: &mut HashMap<(i32, i32), HashMap<i64, usize>>, seen: usize| {
+            let share = seen as f32 / total as f32;
+            votes.retain(|(song_id, other_id), histogram| {
+                let needed = min_overlap * counts[song_id].min(counts[other_id]) as f32;
+                histogram.values().sum::<usize>() as f32 >= share * needed / 2.0
+            });
+        };
+
+        let mut key = None;
+        let mut group = Vec::new();
+        let mut seen = 0;
+        self.for_each_fingerprint(|row| {
+            let fingerprint = row.fingerprint;
+            let row_key = (fingerprint.address, fingerprint.anchor_address);
+            if key != Some(row_key) {
+                vote(&mut votes, &group);
+                group.clear();
+                key = Some(row_key);
+                if votes.len() >= prune_at {
+                    prune(&mut votes, seen);
+                    prune_at = prune_at.max(votes.len() * 2);
+                }
+            }
+            group.push((row.song_id, fingerprint.anchor_time));
+            seen += 1;
+            Ok(())
+        })?;
+        vote(&mut votes, &group);
+
+        let mut pairs = Vec::new();
+        for ((song_id, other_id), histogram) in votes {
+            let near = |bin: i64| {
+                (bin - 1..=bin + 1).filter_map(|bin| histogram.get(&bin).map(|&c| (bin, c)))
+            };
+            let Some((score, best_bin)) = histogram
+                .keys()
+                .map(|&bin| (near(bin).map(|(_, c)| c).sum::<usize>(), bin))
+                .max_by_key(|&(score, bin)| (score, -bin))
+            else {
+                continue;
+            };
+            let weighted = near(best_bin)
+                .map(|(bin, c)| bin as f32 * c as f32)
+                .sum::<f32>();
+            let offset = (weighted / score as f32 + 0.5) * OFFSET_BIN / 1000.0;
+
+            // The shorter song is the one that can be contained in the other
+            let (song_id, other_id, offset) = if counts[&song_id] <= counts[&other_id] {
+                (song_id, other_id, offset)
+            } else {
+                (other_id, song_id, -offset)
+            };
+            let overlap = (score as f32 / counts[&song_id] as f32).min(1.0);
+            if overlap >= min_overlap {
+                pairs.push(DuplicatePair {
+                    song_id,
+                    other_id,
+                    overlap,
+                    offset,
+                });
+            }
+        }
+        pairs.sort_unstable_by_key(|pair| (pair.song_id, pair.other_id));
+
+        // Union-find over the pairs, every group is named by its lowest song id
+        let mut parent: HashMap<i32, i32> = HashMap::new();
+        fn root(parent: &mut HashMap<i32, i32>, song_id: i32) -> i32 {
+            let next = *parent.entry(song_id).or_insert(song_id);
+            if next == song_id {
+                return song_id;
+            }
+            let root = root(parent, next);
+            parent.insert(song_id, root);
+            root
+        }
+        for pair in &pairs {
+            let (a, b) = (
+                root(&mut parent, pair.song_id),
+                root(&mut parent, pair.other_id),
+            );
+            parent.insert(a.max(b), a.min(b));
+        }
+
+        let mut groups: BTreeMap<i32, DuplicateGroup> = BTreeMap::new();
+        for pair in pairs {
+            let group = groups
+                .entry(root(&mut parent, pair.song_id))
+                .or_insert_with(|| DuplicateGroup {
+                    songs: Vec::new(),
+                    pairs: Vec::new(),
+                });
+            group.pairs.push(pair);
+        }
+        for group in groups.values_mut() {
+            let mut song_ids = group
+                .pairs
+                .iter()
+                .flat_map(|pair| [pair.song_id, pair.other_id])
+                .collect::<Vec<_>>();
+            song_ids.sort_unstable();
+            song_ids.dedup();
+            group.songs = song_ids
+                .into_iter()
+                .map(|song_id| Ok((song_id, self.get_song_data(song_id)?)))
+                .collect::<Result<_>>()?;
+        }
+        Ok(groups.into_values().collect())
+    }
+}
+
+// Keys bound by one lookup query, well below SQLite's limit on bound parameters
+const MAX_LOOKUP_CHUNK: usize = 512;
+
+/*
+    Splits sorted keys into chunks of at most MAX_LOOKUP_CHUNK. Every chunk is padded with its last
+    key up to a power of two, which IN ignores, so lookups of any length only prepare a handful of
+    distinct statements and they all stay in the statement cache.
+*/
+fn lookup_chunks(keys: &[u32]) -> impl Iterator<Item = Vec<u32>> + '_ {
+    keys.chunks(MAX_LOOKUP_CHUNK).map(|chunk| {
+        let mut chunk = chunk.to_vec();
+        let size = chunk.len().next_power_of_two().max(16);
+        chunk.resize(size, chunk[chunk.len() - 1]);
+        chunk
+    })
+}
+
+// Rows per multi-row insert into the staging table, 4 bound parameters each
+const BULK_BATCH_ROWS: usize = 200;
+
+/*
+    Fast path for inserting many fingerprints, used instead of `register_fingerprint` when indexing.
+    Rows are appended with multi-row inserts to a temporary staging table without any index. `finish`
+    then moves them into `fingerprints` with one INSERT ... SELECT in primary key order, so the
+    primary key B-tree is built with sequential appends instead of random inserts. The key is the
+    table's only index and can't be dropped, so this sorted insert is its post-load build.
+
+    The loader works on the caller's transaction, nothing is visible until it commits.
+*/
+pub struct BulkLoader<'a> {
+    conn: &'a Connection,
+    pending: Vec<FingerprintData>,
+    staged: usize,
+    progress: Option<Box<dyn FnMut(usize) + 'a>>,
+}
+
+impl<'a> BulkLoader<'a> {
+    pub fn new(conn: &'a Connection) -> Result<Self> {
+        conn.execute(
+            "CREATE TEMP TABLE IF NOT EXISTS fingerprints_staging (
+                address INTEGER NOT NULL,
+                anchorAddress INTEGER NOT NULL,
+                anchorTime INTEGER NOT NULL,
+                songID INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute("DELETE FROM temp.fingerprints_staging", [])?;
+        Ok(BulkLoader {
+            conn,
+            pending: Vec::with_capacity(BULK_BATCH_ROWS),
+            staged: 0,
+            progress: None,
+        })
+    }
+
+    // Called with the number of staged rows after every batch
+    pub fn with_progress(mut self, progress: impl FnMut(usize) + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub fn push(&mut self, fingerprint_data: FingerprintData) -> Result<()> {
+        self.pending.push(fingerprint_data);
+        if self.pending.len() == BULK_BATCH_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        // Full batches always have the same SQL, so their statement stays cached
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "INSERT INTO temp.fingerprints_staging (address, anchorAddress, anchorTime, songID) VALUES {}",
+            vec!["(?, ?, ?, ?)"; self.pending.len()].join(", ")
+        ))?;
+        let params = self.pending.iter().flat_map(|row| {
+            [
+                row.fingerprint.address as i64,
+                row.fingerprint.anchor_address as i64,
+                row.fingerprint.anchor_time as i64,
+                row.song_id as i64,
+            ]
+        });
+        stmt.execute(rusqlite::params_from_iter(params))?;
+
+        self.staged += self.pending.len();
+        self.pending.clear();
+        if let Some(progress) = &mut self.progress {
+            progress(self.staged);
+        }
+        Ok(())
+    }
+
+    // Moves the staged rows into `fingerprints`, returns how many were new
+    pub fn finish(mut self) -> Result<usize> {
+        self.flush()?;
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO fingerprints (address, anchorAddress, anchorTime, songID)
+            SELECT address, anchorAddress, anchorTime, songID FROM temp.fingerprints_staging
+            ORDER BY address, anchorAddress, anchorTime, songID",
+            [],
+        )?;
+        self.conn
+            .execute("DROP TABLE temp.fingerprints_staging", [])?;
+        Ok(inserted)
+    }
+}
+
+// A load that failed half way still gets the journal mode back
+impl Drop for DbClient {
+    fn drop(&mut self) {
+        let _ = self.set_bulk_load_mode(false);
+    }
+}
+
+impl FingerprintStore for DbClient {
+    fn insert_song(&mut self, song_data: &SongData) -> Result<i32> {
+        Ok(self.register_song(song_data)? as i32)
+    }
+
+    fn insert_fingerprints(&mut self, song_id: i32, fingerprints: &[Fingerprint]) -> Result<()> {
+        let tx = self.get_conn()?;
+        let mut loader = BulkLoader::new(&tx)?;
+        for fingerprint in fingerprints {
+            loader.push(FingerprintData {
+                fingerprint: fingerprint.clone(),
+                song_id,
+            })?;
+        }
+        loader.finish()?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn for_each_match(
+        &self,
+        addresses: &[u32],
+        visit: &mut dyn FnMut(FingerprintData) -> Result<()>,
+    ) -> Result<()> {
+        // Chunks are in ascending address order, so the rows of all of them come out sorted
+        for chunk in lookup_chunks(&unique_sorted(addresses.iter().copied())) {
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "SELECT address, anchorAddress, anchorTime, songID FROM fingerprints WHERE address IN ({})
+                ORDER BY address, anchorAddress, anchorTime, songID",
+                vec!["?"; chunk.len()].join(",")
+            ))?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(chunk))?;
+            while let Some(row) = rows.next()? {
+                visit(FingerprintData {
+                    fingerprint: Fingerprint {
+                        address: row.get(0)?,
+                        anchor_address: row.get(1)?,
+                        anchor_time: row.get(2)?,
+                    },
+                    song_id: row.get(3)?,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_song(&self, song_id: i32) -> Result<SongData> {
+        self.get_song_data(song_id)
+    }
+
+    fn search_sub_fingerprints(
+        &self,
+        sub_fingerprints: Vec<SubFingerprint>,
+        rank: usize,
+        min_confidence: f32,
+    ) -> Result<Vec<BitErrorRanking>> {
+        self.search_philips(sub_fingerprints, rank, min_confidence)
+    }
+
+    fn remove_song(&mut self, song_id: i32) -> Result<()> {
+        self.remove_songs(&[song_id])?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<Vec<SongStats>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT songs.id, songs.title, COUNT(*), MAX(anchorTime) - MIN(anchorTime)
+            FROM fingerprints JOIN songs ON songs.id = fingerprints.songID
+            GROUP BY songs.id ORDER BY songs.id",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(SongStats {
+                    song_id: row.get(0)?,
+                    title: row.get(1)?,
+                    hashes: row.get(2)?,
+                    duration_ms: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_with_songs(titles: &[&str]) -> DbClient {
+        let mut client = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for title in titles {
+            let song_id = client
+                .register_song(&SongData {
+                    title: title.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut tx = client.get_conn().unwrap();
+            let fingerprint = FingerprintData {
+                fingerprint: Fingerprint {
+                    address: 1,
+                    anchor_address: 2,
+                    anchor_time: 3,
+                },
+                song_id: song_id as i32,
+            };
+            DbClient::register_fingerprint(&fingerprint, &mut tx).unwrap();
+            tx.commit().unwrap();
+        }
+        client
+    }
+
+    #[test]
+    fn test_remove_songs_all_or_nothing() {
+        let mut client = client_with_songs(&["a", "b", "c"]);
+        let fingerprint_count = |client: &DbClient| -> i64 {
+            client
+                .conn
+                .query_row("SELECT COUNT(*) FROM fingerprints", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        assert!(matches!(
+            client.remove_songs(&[1, 9]),
+            Err(Error::SongNotFound(9))
+        ));
+        assert_eq!(client.song_count().unwrap(), 3);
+        assert_eq!(fingerprint_count(&client), 3);
+
+        let removed = client.remove_songs(&[3, 1, 3]).unwrap();
+        let titles = removed
+            .iter()
+            .map(|(song_id, song)| (*song_id, song.title.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(titles, [(3, "c"), (1, "a")]);
+        assert_eq!(client.song_count().unwrap(), 1);
+        assert_eq!(fingerprint_count(&client), 1);
+    }
+
+    #[test]
+    fn test_remove_song() {
+        let mut client = client_with_songs(&["a", "b"]);
+        client.remove_song(1).unwrap();
+        assert!(matches!(client.remove_song(1), Err(Error::SongNotFound(1))));
+
+        assert_eq!(client.song_count().unwrap(), 1);
+        let song_ids: Vec<i32> = client
+            .conn
+            .prepare("SELECT songID FROM fingerprints")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(song_ids, vec![2]);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let path = std::env::temp_dir().join(format!("shezem-bulk-{}.db3", std::process::id()));
+        let mut client = DbClient::new(&path).unwrap();
+        let journal_mode = |client: &DbClient| -> String {
+            client
+                .conn
+                .pragma_query_value(None, "journal_mode", |row| row.get(0))
+                .unwrap()
+        };
+        // (address, anchor_address, anchor_time, song_id)
+        let mut rows = (0..BULK_BATCH_ROWS as u32 * 2 + 3)
+            .map(|i| (i.wrapping_mul(2654435761), i, i * 10, 1 + i as i32 % 2))
+            .collect::<Vec<_>>();
+
+        client.set_bulk_load_mode(true).unwrap();
+        assert_eq!(journal_mode(&client), "wal");
+        let tx = client.get_conn().unwrap();
+        let mut staged = Vec::new();
+        let mut loader = BulkLoader::new(&tx)
+            .unwrap()
+            .with_progress(|count| staged.push(count));
+        // The duplicate row is ignored like the primary key would
+        for &(address, anchor_address, anchor_time, song_id) in rows.iter().chain(&rows[..1]) {
+            loader
+                .push(FingerprintData {
+                    fingerprint: Fingerprint {
+                        address,
+                        anchor_address,
+                        anchor_time,
+                    },
+                    song_id,
+                })
+                .unwrap();
+        }
+        assert_eq!(loader.finish().unwrap(), rows.len());
+        tx.commit().unwrap();
+        assert_eq!(staged, [200, 400, 404]);
+        client.set_bulk_load_mode(false).unwrap();
+        assert_eq!(journal_mode(&client), "delete");
+
+        let mut loaded = Vec::new();
+        client
+            .for_each_fingerprint(|row| {
+                let fp = row.fingerprint;
+                loaded.push((fp.address, fp.anchor_address, fp.anchor_time, row.song_id));
+                Ok(())
+            })
+            .unwrap();
+        rows.sort_unstable();
+        assert_eq!(loaded, rows);
+
+        drop(client);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_list_songs() {
+        let client = client_with_songs(&["Intro", "Outro", "100% Pure", "Interlude"]);
+        let titles = |filter, limit, offset| {
+            client
+                .list_songs(filter, limit, offset)
+                .unwrap()
+                .into_iter()
+                .map(|(_, song)| song.title)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(titles(None, 2, 1), ["Outro", "100% Pure"]);
+        assert_eq!(titles(Some("int"), 10, 0), ["Intro", "Interlude"]);
+        assert_eq!(titles(Some("0%"), 10, 0), ["100% Pure"]);
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        use crate::{
+            fingerprint::generate_fingerprint,
+            test_utils::{TIME_BASE, WHOLE_MS_FRAMES, excerpt},
+        };
+
+        let song =
+            |seed, frames, start| generate_fingerprint(excerpt(seed, frames, start), TIME_BASE);
+        let mut client = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for (title, fingerprints) in [
+            ("full", song(1, 0..600, 0)),
+            ("other", song(2, 0..600, 0)),
+            ("copy", song(1, 0..600, 0)),
+            (
+                "excerpt",
+                song(1, WHOLE_MS_FRAMES..WHOLE_MS_FRAMES + 100, 0),
+            ),
+        ] {
+            let song_id = client
+                .insert_song(&SongData {
+                    title: title.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            client.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+
+        let groups = client.find_duplicates(0.5).unwrap();
+        // Pruning after every couple of songs keeps the duplicates
+        let pruned = client.find_duplicates_pruned(0.5, 1).unwrap();
+        assert_eq!(format!("{:?}", pruned), format!("{:?}", groups));
+        assert_eq!(groups.len(), 1);
+        let titles = groups[0]
+            .songs
+            .iter()
+            .map(|(_, song)| song.title.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(titles, ["full", "copy", "excerpt"]);
+
+        let pair = |song_id, other_id| {
+            groups[0]
+                .pairs
+                .iter()
+                .find(|pair| (pair.song_id, pair.other_id) == (song_id, other_id))
+                .unwrap()
+        };
+        assert!(pair(1, 3).overlap > 0.9 && pair(1, 3).offset.abs() < 0.1);
+        let excerpt = pair(4, 1);
+        assert!(excerpt.overlap > 0.9);
+        assert!((excerpt.offset - 20.48).abs() < 0.1);
+
+        client
+            .set_fingerprint_mode(FingerprintMode::Invariant)
+            .unwrap();
+        assert!(matches!(
+            client.find_duplicates(0.5),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_philips_needs_more_than_one_matching_block() {
+        let mut client = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        let song_id = client.register_song(&SongData::default()).unwrap() as i32;
+        let hash = |frame: u32, seed: u32| (frame + seed * 100_000).wrapping_mul(2654435761);
+        let tx = client.get_conn().unwrap();
+        for frame in 0..2048 {
+            let sub_fingerprint = SubFingerprint {
+                hash: hash(frame, 0),
+                frame,
+            };
+            DbClient::register_sub_fingerprint(
+                &tx,
+                &SubFingerprintData {
+                    sub_fingerprint,
+                    song_id,
+                },
+            )
+            .unwrap();
+        }
+        tx.commit().unwrap();
+
+        // Four blocks from frame 500 of the song, with all but the first `kept` replaced by noise
+        let query = |kept: u32| {
+            (0..4 * philips::BLOCK_SIZE as u32)
+                .map(|frame| SubFingerprint {
+                    hash: if frame < kept * philips::BLOCK_SIZE as u32 {
+                        hash(frame + 500, 0)
+                    } else {
+                        hash(frame, 1)
+                    },
+                    frame,
+                })
+                .collect::<Vec<_>>()
+        };
+        let found = client.search_philips(query(3), 1, 0.0).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].frame_offset, 500);
+        assert!((found[0].bit_error_rate - 0.125).abs() < 0.02);
+        assert!(client.search_philips(query(1), 1, 0.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lookup_more_addresses_than_parameters() {
+        let client = client_with_songs(&["a", "b"]);
+        // Well past SQLite's 32766 bound parameters, with duplicates and the stored address last
+        let mut addresses = (2..100_000).rev().collect::<Vec<u32>>();
+        addresses.extend([5, 5, 1]);
+        let rows = client.lookup_addresses(&addresses).unwrap();
+        assert_eq!(
+            rows.iter().map(|row| row.song_id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert!(client.lookup_addresses(&[]).unwrap().is_empty());
+    }
 }
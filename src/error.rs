@@ -0,0 +1,102 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{}{source}", path.as_ref().map(|path| format!("{}: ", path.display())).unwrap_or_default())]
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+
+    // The audio file exists but isn't something the decoder understands
+    #[error("{}: cannot decode audio: {reason}", path.display())]
+    Decode { path: PathBuf, reason: String },
+
+    #[error("{}: not enough audio to fingerprint", path.display())]
+    InsufficientAudio { path: PathBuf },
+
+    #[error("database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    #[error("{}: no index, run `index` on the folder first", path.display())]
+    NoIndex { path: PathBuf },
+
+    // The index or query was made by another version or with other options
+    #[error("incompatible index: {0}")]
+    IncompatibleIndex(String),
+
+    #[error("corrupted index: {0}")]
+    CorruptIndex(String),
+
+    #[error("invalid fingerprint file: {0}")]
+    InvalidFingerprint(String),
+
+    #[error("no song with id {0}")]
+    SongNotFound(i32),
+
+    #[error("no song is titled \"{0}\"")]
+    SongTitleNotFound(String),
+
+    #[error("{0}")]
+    Unsupported(String),
+}
+
+impl Error {
+    /*
+        Exit code of the CLI for this error. 1 is left for panics and 2 for usage errors reported by
+        clap, the rest are stable so that scripts can tell failures apart.
+    */
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Io { .. } => 3,
+            Error::Decode { .. } => 4,
+            Error::InsufficientAudio { .. } => 5,
+            Error::Database(_) => 6,
+            Error::NoIndex { .. } => 7,
+            Error::IncompatibleIndex(_) => 8,
+            Error::CorruptIndex(_) => 9,
+            Error::InvalidFingerprint(_) => 10,
+            Error::SongNotFound(_) | Error::SongTitleNotFound(_) => 11,
+            Error::Unsupported(_) => 12,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io { path: None, source }
+    }
+}
+
+// Names the file an IO error happened on, unless it already names one
+pub(crate) trait IoContext<T> {
+    fn with_path(self, path: &Path) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn with_path(self, path: &Path) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            path: Some(path.to_path_buf()),
+            source,
+        })
+    }
+}
+
+impl<T> IoContext<T> for Result<T> {
+    fn with_path(self, path: &Path) -> Result<T> {
+        self.map_err(|err| match err {
+            Error::Io { path: None, source } => Error::Io {
+                path: Some(path.to_path_buf()),
+                source,
+            },
+            err => err,
+        })
+    }
+}
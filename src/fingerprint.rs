@@ -1,4 +1,73 @@
-use crate::{NEIGHBORHOOD_SIZE, spectrogram::Peak};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    NEIGHBORHOOD_SIZE,
+    sample::Sample,
+    spectrogram::{
+        self, DensityController, Peak, TimeBase, filter_spectrogram, generate_spectrogram,
+    },
+};
+
+// Common interface of the fingerprinting algorithms, each one works on a downsampled mono sample
+pub trait Fingerprinter {
+    type Output;
+
+    fn generate(&self, sample: &Sample) -> Vec<Self::Output>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    // Spectrogram peak landmarks, see `generate_fingerprint`
+    #[default]
+    Landmark,
+    // Haitsma-Kalker band energy sub-fingerprints, see `philips`
+    Philips,
+}
+
+impl Algorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Landmark => "landmark",
+            Algorithm::Philips => "philips",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "landmark" => Some(Algorithm::Landmark),
+            "philips" => Some(Algorithm::Philips),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FingerprintMode {
+    // Absolute frequency bins and delta times, see `build_address`
+    #[default]
+    Landmark,
+    // Frequency ratios and time-delta ratios of peak triplets, see `build_invariant_address`
+    Invariant,
+}
+
+impl FingerprintMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FingerprintMode::Landmark => "landmark",
+            FingerprintMode::Invariant => "invariant",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "landmark" => Some(FingerprintMode::Landmark),
+            "invariant" => Some(FingerprintMode::Invariant),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct FingerprintData {
@@ -6,42 +75,171 @@ pub struct FingerprintData {
     pub song_id: i32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Fingerprint {
     pub address: u32,
     pub anchor_address: u32,
     pub anchor_time: u32,
 }
 
-pub fn generate_fingerprint(mut peaks: Vec<Peak>) -> Vec<Fingerprint> {
-    peaks.sort_by(|a, b| {
-        a.time
-            .partial_cmp(&b.time)
-            .unwrap()
-            .then(a.freq.partial_cmp(&b.freq).unwrap())
-    });
+pub const WINDOW_SIZE: spectrogram::WindowSize = spectrogram::WindowSize::S1024;
+pub const HOP_SIZE: usize = 512;
+
+pub struct LandmarkFingerprinter {
+    pub mode: FingerprintMode,
+    // Peak budget per second, None keeps every peak
+    pub density: Option<DensityController>,
+}
+
+impl Fingerprinter for LandmarkFingerprinter {
+    type Output = Fingerprint;
+
+    fn generate(&self, sample: &Sample) -> Vec<Fingerprint> {
+        let window_size: usize = WINDOW_SIZE.into();
+        let mut spectrogram =
+            generate_spectrogram(&sample.sample, WINDOW_SIZE, window_size - HOP_SIZE);
+        let time_base = TimeBase {
+            hop_size: HOP_SIZE,
+            sample_rate: sample.sample_rate,
+        };
+        let mut peaks = filter_spectrogram(&mut spectrogram);
+        if let Some(density) = self.density {
+            peaks = density.apply(peaks, time_base);
+        }
+        generate_fingerprint_with_mode(peaks, time_base, self.mode)
+    }
+}
+
+pub fn generate_fingerprint(mut peaks: Vec<Peak>, time_base: TimeBase) -> Vec<Fingerprint> {
+    peaks.sort();
 
     let mut addresses = Vec::new();
-    for i in 0..(peaks.len() - NEIGHBORHOOD_SIZE) {
-        let anchor_address = build_address(&peaks[i], &peaks[i + NEIGHBORHOOD_SIZE]);
+    for i in 0..peaks.len().saturating_sub(NEIGHBORHOOD_SIZE) {
+        let anchor_address = build_address(&peaks[i], &peaks[i + NEIGHBORHOOD_SIZE], time_base);
         for j in i..(i + NEIGHBORHOOD_SIZE) {
-            let address = build_address(&peaks[i], &peaks[j]);
+            let address = build_address(&peaks[i], &peaks[j], time_base);
             addresses.push(Fingerprint {
                 address,
                 anchor_address,
-                anchor_time: (peaks[i].time * 1000.0) as u32,
+                anchor_time: time_base.frame_to_ms(peaks[i].frame),
             });
         }
     }
     addresses
 }
 
-pub fn build_address(peak_a: &Peak, peak_b: &Peak) -> u32 {
-    let delta_time = (peak_b.time - peak_a.time) * 1000.0;
+pub fn generate_fingerprint_with_mode(
+    peaks: Vec<Peak>,
+    time_base: TimeBase,
+    mode: FingerprintMode,
+) -> Vec<Fingerprint> {
+    match mode {
+        FingerprintMode::Landmark => generate_fingerprint(peaks, time_base),
+        FingerprintMode::Invariant => generate_invariant_fingerprint(peaks, time_base),
+    }
+}
+
+/*
+    Playing a recording k times faster multiplies every frequency by k and divides every time
+    interval by k, so absolute bins and delta times no longer match. For a triplet of peaks
+    (anchor, a, b) the ratios freq_a / freq_anchor, freq_b / freq_anchor and dt_a / dt_b are
+    unchanged by such a speed change, so we hash those instead.
+
+    The anchor_address field carries dt_b in milliseconds. Comparing it between the query and
+    the reference gives the speed factor of every match.
+*/
+pub fn generate_invariant_fingerprint(
+    mut peaks: Vec<Peak>,
+    time_base: TimeBase,
+) -> Vec<Fingerprint> {
+    peaks.sort();
+
+    let mut addresses = Vec::new();
+    for i in 0..peaks.len().saturating_sub(NEIGHBORHOOD_SIZE) {
+        for j in (i + 1)..=(i + NEIGHBORHOOD_SIZE) {
+            for k in (j + 1)..=(i + NEIGHBORHOOD_SIZE) {
+                let (anchor, a, b) = (&peaks[i], &peaks[j], &peaks[k]);
+                if a.frame <= anchor.frame || b.frame <= a.frame {
+                    continue;
+                }
+                let anchor_time = time_base.frame_to_ms(anchor.frame);
+                addresses.push(Fingerprint {
+                    address: build_invariant_address(anchor, a, b),
+                    anchor_address: time_base.frame_to_ms(b.frame) - anchor_time,
+                    anchor_time,
+                });
+            }
+        }
+    }
+    addresses
+}
+
+// Steps per octave used to quantize frequency ratios
+const RATIO_STEPS_PER_OCTAVE: f32 = 12.0;
+// Number of steps used to quantize the time-delta ratio, which lies in (0, 1)
+const TIME_RATIO_STEPS: f32 = 64.0;
+
+pub fn build_invariant_address(anchor: &Peak, peak_a: &Peak, peak_b: &Peak) -> u32 {
+    let quantize_ratio = |freq: u32| {
+        let ratio = freq.max(1) as f32 / anchor.freq.max(1) as f32;
+        ((ratio.log2() * RATIO_STEPS_PER_OCTAVE).round() + 128.0).clamp(0.0, 255.0) as u32
+    };
+    // Both deltas are in frames, the ratio doesn't need a time base
+    let time_ratio = (peak_a.frame - anchor.frame) as f32 / (peak_b.frame - anchor.frame) as f32;
+    /*
+        8 bits for storing the log ratio of peak_a.freq and anchor.freq
+        8 bits for storing the log ratio of peak_b.freq and anchor.freq
+        8 bits for storing the ratio of the two delta times
+    */
+    (quantize_ratio(peak_a.freq) << 16)
+        | (quantize_ratio(peak_b.freq) << 8)
+        | (time_ratio * TIME_RATIO_STEPS).round() as u32
+}
+
+pub fn build_address(peak_a: &Peak, peak_b: &Peak, time_base: TimeBase) -> u32 {
+    let delta_time = time_base.frame_to_ms(peak_b.frame) - time_base.frame_to_ms(peak_a.frame);
     /*
         9 bits for storing peak_a.freq
         9 bits for storing peak_b.freq
         14 bits for storing delta_time
     */
-    (peak_a.freq << 23) | (peak_b.freq << 14) | delta_time as u32
+    (peak_a.freq << 23) | (peak_b.freq << 14) | delta_time
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME_BASE: TimeBase = TimeBase {
+        hop_size: 512,
+        sample_rate: 11025,
+    };
+
+    fn peak(frame: u32, freq: u32) -> Peak {
+        Peak {
+            frame,
+            freq,
+            magnitude: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_too_few_peaks() {
+        let peaks = (0..NEIGHBORHOOD_SIZE as u32)
+            .map(|i| peak(i, 100))
+            .collect();
+        assert!(generate_fingerprint(peaks, TIME_BASE).is_empty());
+        assert!(generate_invariant_fingerprint(Vec::new(), TIME_BASE).is_empty());
+    }
+
+    #[test]
+    fn test_input_order_does_not_matter() {
+        let peaks = (0..20).map(|i| peak(i / 2, 30 + i * 7)).collect::<Vec<_>>();
+        let mut reversed = peaks.clone();
+        reversed.reverse();
+        assert_eq!(
+            generate_fingerprint(peaks, TIME_BASE),
+            generate_fingerprint(reversed, TIME_BASE)
+        );
+    }
 }
@@ -0,0 +1,434 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    NEIGHBORHOOD_SIZE,
+    error::{Error, IoContext, Result},
+    fingerprint::{self, Algorithm, Fingerprint, FingerprintMode},
+    philips::{self, SubFingerprint},
+};
+
+/*
+    Binary layout of a fingerprint file, all integers little-endian:
+
+        magic           4 bytes  "SHZF"
+        version         u16
+        algorithm       u8       0 = landmark, 1 = philips
+        mode            u8       0 = landmark, 1 = invariant
+        sample_rate     u32      sample rate the fingerprints were computed at
+        window_size     u32      FFT window size in samples
+        hop_size        u32      distance between consecutive windows in samples
+        neighborhood    u32      peaks grouped under one anchor
+        count           u32      number of records
+
+    Landmark records are (address u32, anchor_address u32, anchor_time delta) and Philips records
+    are (hash u32, frame delta). Deltas are taken from the previous record and stored as zigzag
+    varints, since times barely move from one record to the next.
+*/
+
+const MAGIC: &[u8; 4] = b"SHZF";
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FingerprintConfig {
+    pub algorithm: Algorithm,
+    pub mode: FingerprintMode,
+    pub sample_rate: u32,
+    pub window_size: u32,
+    pub hop_size: u32,
+    pub neighborhood: u32,
+}
+
+impl FingerprintConfig {
+    pub fn new(algorithm: Algorithm, mode: FingerprintMode, sample_rate: usize) -> Self {
+        let (window_size, hop_size): (usize, usize) = match algorithm {
+            Algorithm::Landmark => (fingerprint::WINDOW_SIZE.into(), fingerprint::HOP_SIZE),
+            Algorithm::Philips => (philips::WINDOW_SIZE.into(), philips::HOP_SIZE),
+        };
+        FingerprintConfig {
+            algorithm,
+            mode,
+            sample_rate: sample_rate as u32,
+            window_size: window_size as u32,
+            hop_size: hop_size as u32,
+            neighborhood: NEIGHBORHOOD_SIZE as u32,
+        }
+    }
+
+    // Fingerprints made with any other setting don't match those of `index`, they can't be searched in it
+    pub fn check_compatible(&self, index: &FingerprintConfig) -> Result<()> {
+        if self != index {
+            return Err(Error::IncompatibleIndex(format!(
+                "query was fingerprinted with {} but the index uses {}",
+                self, index
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FingerprintConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} at {} Hz, window {}, hop {}, neighborhood {}",
+            self.algorithm.as_str(),
+            self.mode.as_str(),
+            self.sample_rate,
+            self.window_size,
+            self.hop_size,
+            self.neighborhood
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fingerprints {
+    Landmark(Vec<Fingerprint>),
+    Philips(Vec<SubFingerprint>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FingerprintFile {
+    pub version: u16,
+    pub config: FingerprintConfig,
+    pub fingerprints: Fingerprints,
+}
+
+impl FingerprintFile {
+    pub fn new(config: FingerprintConfig, fingerprints: Fingerprints) -> Self {
+        FingerprintFile {
+            version: FORMAT_VERSION,
+            config,
+            fingerprints,
+        }
+    }
+
+    pub fn write_binary<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let config = &self.config;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&[algorithm_tag(config.algorithm), mode_tag(config.mode)])?;
+        for value in [
+            config.sample_rate,
+            config.window_size,
+            config.hop_size,
+            config.neighborhood,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+
+        match &self.fingerprints {
+            Fingerprints::Landmark(fingerprints) => {
+                writer.write_all(&(fingerprints.len() as u32).to_le_bytes())?;
+                let mut previous = 0;
+                for fp in fingerprints {
+                    writer.write_all(&fp.address.to_le_bytes())?;
+                    writer.write_all(&fp.anchor_address.to_le_bytes())?;
+                    write_varint(writer, zigzag(fp.anchor_time as i64 - previous))?;
+                    previous = fp.anchor_time as i64;
+                }
+            }
+            Fingerprints::Philips(sub_fingerprints) => {
+                writer.write_all(&(sub_fingerprints.len() as u32).to_le_bytes())?;
+                let mut previous = 0;
+                for s in sub_fingerprints {
+                    writer.write_all(&s.hash.to_le_bytes())?;
+                    write_varint(writer, zigzag(s.frame as i64 - previous))?;
+                    previous = s.frame as i64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_binary<R: Read>(reader: &mut R) -> Result<Self> {
+        let magic: [u8; 4] = read_array(reader)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a fingerprint file"));
+        }
+
+        let version = u16::from_le_bytes(read_array(reader)?);
+        check_version(version)?;
+
+        let [algorithm, mode] = read_array(reader)?;
+        let config = FingerprintConfig {
+            algorithm: match algorithm {
+                0 => Algorithm::Landmark,
+                1 => Algorithm::Philips,
+                _ => return Err(invalid(format!("unknown algorithm {}", algorithm))),
+            },
+            mode: match mode {
+                0 => FingerprintMode::Landmark,
+                1 => FingerprintMode::Invariant,
+                _ => return Err(invalid(format!("unknown fingerprint mode {}", mode))),
+            },
+            sample_rate: read_u32(reader)?,
+            window_size: read_u32(reader)?,
+            hop_size: read_u32(reader)?,
+            neighborhood: read_u32(reader)?,
+        };
+
+        let count = read_u32(reader)? as usize;
+        let mut previous = 0i64;
+        let fingerprints = match config.algorithm {
+            Algorithm::Landmark => {
+                let mut fingerprints = Vec::new();
+                for _ in 0..count {
+                    let address = read_u32(reader)?;
+                    let anchor_address = read_u32(reader)?;
+                    previous += unzigzag(read_varint(reader)?);
+                    fingerprints.push(Fingerprint {
+                        address,
+                        anchor_address,
+                        anchor_time: time(previous)?,
+                    });
+                }
+                Fingerprints::Landmark(fingerprints)
+            }
+            Algorithm::Philips => {
+                let mut sub_fingerprints = Vec::new();
+                for _ in 0..count {
+                    let hash = read_u32(reader)?;
+                    previous += unzigzag(read_varint(reader)?);
+                    sub_fingerprints.push(SubFingerprint {
+                        hash,
+                        frame: time(previous)?,
+                    });
+                }
+                Fingerprints::Philips(sub_fingerprints)
+            }
+        };
+
+        Ok(FingerprintFile {
+            version,
+            config,
+            fingerprints,
+        })
+    }
+
+    pub fn save(&self, path: &PathBuf, json: bool) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path).with_path(path)?);
+        if json {
+            serde_json::to_writer_pretty(&mut writer, self)
+                .map_err(io::Error::from)
+                .with_path(path)?;
+        } else {
+            self.write_binary(&mut writer)?;
+        }
+        writer.flush().with_path(path)?;
+        Ok(())
+    }
+
+    // Accepts both the binary and the JSON form
+    pub fn load(path: &PathBuf) -> Result<Self> {
+        let mut bytes = Vec::new();
+        BufReader::new(File::open(path).with_path(path)?)
+            .read_to_end(&mut bytes)
+            .with_path(path)?;
+        if bytes.starts_with(MAGIC) {
+            return Self::read_binary(&mut bytes.as_slice());
+        }
+
+        let file: FingerprintFile =
+            serde_json::from_slice(&bytes).map_err(|err| invalid(err.to_string()))?;
+        check_version(file.version)?;
+        Ok(file)
+    }
+}
+
+fn algorithm_tag(algorithm: Algorithm) -> u8 {
+    match algorithm {
+        Algorithm::Landmark => 0,
+        Algorithm::Philips => 1,
+    }
+}
+
+fn mode_tag(mode: FingerprintMode) -> u8 {
+    match mode {
+        FingerprintMode::Landmark => 0,
+        FingerprintMode::Invariant => 1,
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidFingerprint(reason.into())
+}
+
+fn check_version(version: u16) -> Result<()> {
+    if version > FORMAT_VERSION {
+        return Err(invalid(format!(
+            "version {} is newer than the supported version {}",
+            version, FORMAT_VERSION
+        )));
+    }
+    Ok(())
+}
+
+fn time(value: i64) -> Result<u32> {
+    u32::try_from(value).map_err(|_| invalid(format!("time {} is out of range", value)))
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader
+        .read_exact(&mut buf)
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("file is truncated"),
+            _ => err.into(),
+        })?;
+    Ok(buf)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+pub(crate) fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub(crate) fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+pub(crate) fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> Result<()> {
+    while value >= 0x80 {
+        writer.write_all(&[(value as u8) | 0x80])?;
+        value >>= 7;
+    }
+    writer.write_all(&[value as u8])?;
+    Ok(())
+}
+
+pub(crate) fn read_varint<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let [byte] = read_array(reader)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("malformed varint"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(algorithm: Algorithm) -> FingerprintConfig {
+        FingerprintConfig {
+            algorithm,
+            mode: FingerprintMode::Landmark,
+            sample_rate: 11025,
+            window_size: 1024,
+            hop_size: 512,
+            neighborhood: 5,
+        }
+    }
+
+    #[test]
+    fn test_landmark_roundtrip() {
+        let file = FingerprintFile::new(
+            config(Algorithm::Landmark),
+            Fingerprints::Landmark(vec![
+                Fingerprint {
+                    address: u32::MAX,
+                    anchor_address: 7,
+                    anchor_time: 1200,
+                },
+                Fingerprint {
+                    address: 3,
+                    anchor_address: 0,
+                    anchor_time: 1000,
+                },
+            ]),
+        );
+        let mut bytes = Vec::new();
+        file.write_binary(&mut bytes).unwrap();
+        assert_eq!(
+            FingerprintFile::read_binary(&mut bytes.as_slice()).unwrap(),
+            file
+        );
+    }
+
+    #[test]
+    fn test_philips_roundtrip() {
+        let file = FingerprintFile::new(
+            config(Algorithm::Philips),
+            Fingerprints::Philips(vec![
+                SubFingerprint { hash: 42, frame: 1 },
+                SubFingerprint {
+                    hash: 0xffff0000,
+                    frame: 2,
+                },
+            ]),
+        );
+        let mut bytes = Vec::new();
+        file.write_binary(&mut bytes).unwrap();
+        assert_eq!(
+            FingerprintFile::read_binary(&mut bytes.as_slice()).unwrap(),
+            file
+        );
+    }
+
+    #[test]
+    fn test_rejects_newer_version() {
+        let mut bytes = Vec::new();
+        FingerprintFile::new(config(Algorithm::Landmark), Fingerprints::Landmark(vec![]))
+            .write_binary(&mut bytes)
+            .unwrap();
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(FingerprintFile::read_binary(&mut bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        let index = config(Algorithm::Landmark);
+        assert!(index.check_compatible(&index).is_ok());
+        for query in [
+            config(Algorithm::Philips),
+            FingerprintConfig {
+                sample_rate: 12000,
+                ..index
+            },
+            FingerprintConfig {
+                hop_size: 256,
+                ..index
+            },
+            FingerprintConfig {
+                neighborhood: 4,
+                ..index
+            },
+        ] {
+            assert!(matches!(
+                query.check_compatible(&index),
+                Err(Error::IncompatibleIndex(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_rejects_truncated_file() {
+        let mut bytes = Vec::new();
+        FingerprintFile::new(
+            config(Algorithm::Philips),
+            Fingerprints::Philips(vec![SubFingerprint { hash: 42, frame: 1 }]),
+        )
+        .write_binary(&mut bytes)
+        .unwrap();
+        bytes.pop();
+        assert!(matches!(
+            FingerprintFile::read_binary(&mut bytes.as_slice()),
+            Err(Error::InvalidFingerprint(_))
+        ));
+    }
+}
@@ -1,90 +1,1144 @@
-use std::{collections::VecDeque, fs, path::PathBuf};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
 
-use anyhow::{Ok, Result};
-use db::{DbClient, SongData};
-use fingerprint::{FingerprintData, generate_fingerprint};
+use batch::BatchFormat;
+use db::{BulkLoader, DbClient, SongData};
+use error::IoContext;
+use fingerprint::{
+    Algorithm, FingerprintData, FingerprintMode, Fingerprinter, LandmarkFingerprinter,
+};
+use format::{FingerprintConfig, FingerprintFile, Fingerprints};
+use memory::MemoryStore;
+use metadata::{FileInfo, read_file_info, read_tags};
+use mmap_index::MmapIndex;
+use monitor::{Monitor, MonitorOptions};
+use philips::{PhilipsFingerprinter, SubFingerprintData};
+use ranking::{RankingData, RankingOptions};
 use sample::Sample;
-use spectrogram::{filter_spectrogram, generate_spectrogram};
+use spectrogram::DensityController;
+use store::FingerprintStore;
 
+pub mod batch;
 pub mod db;
+pub mod error;
 pub mod fingerprint;
+pub mod format;
+pub mod memory;
+pub mod metadata;
+pub mod migrations;
+pub mod mmap_index;
+pub mod monitor;
+pub mod philips;
+pub mod ranking;
 pub mod sample;
+pub mod segment;
 pub mod spectrogram;
+pub mod store;
+#[cfg(test)]
+mod test_utils;
 pub mod utils;
 
+pub use error::{Error, Result};
+
 const NEIGHBORHOOD_SIZE: usize = 5;
+// Decoded audio is fingerprinted at a quarter of its sample rate
+const DOWNSAMPLE_FACTOR: usize = 4;
+// Share of the index's peak budget queries keep by default, in percent
+const QUERY_DENSITY_PERCENT: usize = 75;
 
-pub fn index_folder(path: &PathBuf, database_path: &PathBuf) -> Result<()> {
-    let entries: Vec<_> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
-    //this stack hold the song ids
-    let mut stack = VecDeque::new();
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IndexOptions {
+    pub algorithm: Algorithm,
+    pub mode: FingerprintMode,
+    // Peaks kept per second, see `DensityController`. None keeps every peak
+    pub density: Option<usize>,
+}
 
-    //First we insert the song information into the song database
-    {
-        let db_client = DbClient::new(database_path);
-        for entry in &entries {
-            if entry.path().extension().and_then(|e| e.to_str()) != Some("mp3") {
-                continue;
-            }
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IndexSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+enum IndexAction {
+    Add(SongData),
+    // The file changed, its fingerprints are replaced
+    Update(i32, SongData),
+    // Only the modification time changed, the content is the same
+    Touch(i32, SongData),
+}
+
+fn audio_files(folder: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(folder)
+        .with_path(folder)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_path(folder)?;
+    entries.retain(|path| path.extension().and_then(|e| e.to_str()) == Some("mp3"));
+    entries.sort();
+    Ok(entries)
+}
+
+// Song row of a file, the title falls back to the file name when the tags don't have one
+fn song_data(path: &Path, file_info: FileInfo, modified_at: Option<i64>) -> SongData {
+    let tags = read_tags(path);
+    let title = tags
+        .title
+        .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().to_string());
+    SongData {
+        title,
+        artist: tags.artist,
+        album: tags.album,
+        track_number: tags.track_number,
+        isrc: tags.isrc,
+        duration_ms: tags.duration_ms,
+        path: Some(path.to_string_lossy().to_string()),
+        file_size: Some(file_info.size),
+        content_hash: Some(file_info.content_hash),
+        modified_at,
+    }
+}
+
+/*
+    Songs indexed before paths were recorded are claimed by the file with the same content hash, or
+    failing that by the file whose name their title was taken from.
+*/
+fn take_legacy_song(
+    legacy: &mut Vec<(i32, SongData)>,
+    path: &Path,
+    content_hash: &str,
+) -> Option<(i32, SongData)> {
+    let stem = path.file_stem()?.to_string_lossy();
+    let index = legacy
+        .iter()
+        .position(|(_, song)| song.content_hash.as_deref() == Some(content_hash))
+        .or_else(|| {
+            legacy
+                .iter()
+                .position(|(_, song)| song.content_hash.is_none() && song.title == stem)
+        })?;
+    Some(legacy.swap_remove(index))
+}
+
+fn modified_at(metadata: &fs::Metadata) -> Option<i64> {
+    let modified = metadata.modified().ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    i64::try_from(since_epoch.as_nanos()).ok()
+}
+
+/*
+    Indexing is incremental: a file whose path, size and modification time match its row in the index is
+    skipped without being read, and a file whose content hash still matches is not fingerprinted again.
+    Changed files get their fingerprints replaced under the same song id. With `prune`, songs indexed
+    from this folder whose files no longer exist are removed. Songs from an index made before paths
+    were recorded are matched to their files as in `take_legacy_song`.
+
+    Changing the algorithm, mode or density fingerprints every song again, so every song of the index
+    has to be in the folder.
+
+    Every write happens in one transaction, so an interrupted run leaves the index as it was.
+*/
+pub fn index_folder(
+    path: &PathBuf,
+    database_path: &PathBuf,
+    options: IndexOptions,
+    prune: bool,
+) -> Result<IndexSummary> {
+    let IndexOptions {
+        algorithm,
+        mode,
+        density,
+    } = options;
+    let folder = fs::canonicalize(path).with_path(path)?;
+    let entries = audio_files(&folder)?;
+
+    if let Some(parent) = database_path.parent() {
+        fs::create_dir_all(parent).with_path(parent)?;
+    }
+    let mut db_client = DbClient::new(database_path)?;
 
-            let title = entry
-                .path()
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            let song_id = db_client.register_song(&SongData { title })?;
-            stack.push_back(song_id as i32);
+    // Fingerprints made with other options can't be mixed, everything has to be fingerprinted again
+    let options_changed = db_client.algorithm()? != algorithm
+        || db_client.fingerprint_mode()? != mode
+        || db_client.density()? != density;
+    let song_count = db_client.song_count()?;
+    let reindex_all = options_changed && song_count > 0;
+
+    let mut legacy = db_client.legacy_songs()?;
+    let mut summary = IndexSummary::default();
+    let mut actions = Vec::new();
+    for path in entries {
+        let metadata = fs::metadata(&path).with_path(&path)?;
+        let path_str = path.to_string_lossy().to_string();
+        let mut existing = db_client.find_song_by_path(&path_str)?;
+        let modified_at = modified_at(&metadata);
+
+        if let Some((_, song)) = &existing
+            && !reindex_all
+            && song.file_size == Some(metadata.len())
+            && song.modified_at.is_some()
+            && song.modified_at == modified_at
+        {
+            summary.unchanged += 1;
+            continue;
+        }
+
+        let file_info = read_file_info(&path)?;
+        if existing.is_none() {
+            existing = take_legacy_song(&mut legacy, &path, &file_info.content_hash);
+        }
+        if let Some((song_id, song)) = &existing
+            && !reindex_all
+            && song.content_hash.as_ref() == Some(&file_info.content_hash)
+        {
+            summary.unchanged += 1;
+            actions.push(IndexAction::Touch(
+                *song_id,
+                SongData {
+                    path: Some(path_str),
+                    file_size: Some(file_info.size),
+                    modified_at,
+                    ..song.clone()
+                },
+            ));
+            continue;
         }
+
+        let song = song_data(&path, file_info, modified_at);
+        actions.push(match existing {
+            Some((song_id, _)) => IndexAction::Update(song_id, song),
+            None => IndexAction::Add(song),
+        });
     }
 
-    //Then we bulk insert all the fingerprints into the fingerprint database
-    {
-        let mut db_client = DbClient::new(database_path);
-        let mut tx = db_client.get_conn();
-        for entry in entries {
-            if entry.path().extension().and_then(|e| e.to_str()) != Some("mp3") {
+    let removed = if prune {
+        db_client
+            .song_paths()?
+            .into_iter()
+            .filter(|(_, song_path)| {
+                let song_path = Path::new(song_path);
+                song_path.starts_with(&folder) && !song_path.exists()
+            })
+            .map(|(song_id, _)| song_id)
+            .collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+
+    if reindex_all {
+        let rebuilt = actions
+            .iter()
+            .filter(|action| matches!(action, IndexAction::Update(..)))
+            .count();
+        let stale = song_count - rebuilt - removed.len();
+        if stale > 0 {
+            return Err(Error::IncompatibleIndex(format!(
+                "the options differ from the ones the index was built with, and {} of its songs are not in {} to be fingerprinted again, remove them or index into a new database",
+                stale,
+                folder.display()
+            )));
+        }
+    }
+
+    db_client.set_bulk_load_mode(true)?;
+    let tx = db_client.get_conn()?;
+    DbClient::delete_songs(&tx, &removed)?;
+    summary.removed = removed.len();
+
+    let updated = actions
+        .iter()
+        .filter_map(|action| match action {
+            IndexAction::Update(song_id, _) => Some(*song_id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    DbClient::clear_fingerprints(&tx, &updated)?;
+    if algorithm == Algorithm::Philips {
+        DbClient::drop_sub_fingerprint_index(&tx)?;
+    }
+
+    let mut loader = BulkLoader::new(&tx)?;
+    let mut sample_rate = None;
+    for action in actions {
+        let (song_id, song) = match action {
+            IndexAction::Touch(song_id, song) => {
+                DbClient::write_song(&tx, song_id, &song)?;
                 continue;
             }
+            IndexAction::Update(song_id, song) => {
+                DbClient::write_song(&tx, song_id, &song)?;
+                summary.updated += 1;
+                (song_id, song)
+            }
+            IndexAction::Add(song) => {
+                summary.added += 1;
+                (DbClient::insert_song(&tx, &song)? as i32, song)
+            }
+        };
 
-            let mut sample = Sample::read_mp3(&entry.path())?;
-            sample = sample.downsample(4);
-            let mut spectrogram =
-                generate_spectrogram(&sample.sample, spectrogram::WindowSize::S1024, 512);
-            let peaks = filter_spectrogram(&mut spectrogram, sample.sample_rate);
-            let fingerprints = generate_fingerprint(peaks);
-
-            let song_id = stack.pop_front().unwrap();
-            for fingerprint in fingerprints {
-                DbClient::register_fingerprint(
-                    &FingerprintData {
+        let sample = read_sample(&PathBuf::from(song.path.as_deref().unwrap()))?;
+        DbClient::set_song_duration(&tx, song_id, sample.duration_ms())?;
+        sample_rate = Some(sample.sample_rate);
+        match algorithm {
+            Algorithm::Landmark => {
+                let fingerprinter = LandmarkFingerprinter {
+                    mode,
+                    density: density.map(DensityController::new),
+                };
+                for fingerprint in fingerprinter.generate(&sample) {
+                    loader.push(FingerprintData {
                         fingerprint,
                         song_id,
-                    },
-                    &mut tx,
-                )?;
+                    })?;
+                }
+            }
+            Algorithm::Philips => {
+                for sub_fingerprint in PhilipsFingerprinter.generate(&sample) {
+                    DbClient::register_sub_fingerprint(
+                        &tx,
+                        &SubFingerprintData {
+                            sub_fingerprint,
+                            song_id,
+                        },
+                    )?;
+                }
             }
         }
-        tx.commit()?;
+    }
+    loader.finish()?;
+    if algorithm == Algorithm::Philips {
+        DbClient::build_sub_fingerprint_index(&tx)?;
+    }
+    DbClient::save_index_options(&tx, algorithm, mode, density, sample_rate)?;
+    tx.commit()?;
+    db_client.set_bulk_load_mode(false)?;
+    Ok(summary)
+}
+
+/*
+    Fingerprints every file of the folder into a `MemoryStore`, nothing is written to disk. Only
+    landmark fingerprints can be kept in memory.
+*/
+pub fn index_in_memory(path: &PathBuf, options: IndexOptions) -> Result<MemoryStore> {
+    if options.algorithm != Algorithm::Landmark {
+        return Err(Error::Unsupported(format!(
+            "the in-memory store only holds landmark fingerprints, not {}",
+            options.algorithm.as_str()
+        )));
+    }
+    let fingerprinter = LandmarkFingerprinter {
+        mode: options.mode,
+        density: options.density.map(DensityController::new),
+    };
+
+    let mut store = MemoryStore::new();
+    for path in audio_files(&fs::canonicalize(path).with_path(path)?)? {
+        let modified_at = modified_at(&fs::metadata(&path).with_path(&path)?);
+        let mut song = song_data(&path, read_file_info(&path)?, modified_at);
+        let sample = read_sample(&path)?;
+        song.duration_ms = Some(sample.duration_ms());
+
+        let song_id = store.insert_song(&song)?;
+        store.insert_fingerprints(song_id, &fingerprinter.generate(&sample))?;
+    }
+    Ok(store)
+}
+
+// Searches any landmark store, the query is fingerprinted with the options the store was built with
+pub fn search_store<S: FingerprintStore + ?Sized>(
+    store: &S,
+    query_file: &PathBuf,
+    options: IndexOptions,
+    ranking_options: &RankingOptions,
+) -> Result<Vec<RankingData>> {
+    let Fingerprints::Landmark(fingerprints) =
+        compute_fingerprints(query_file, options)?.fingerprints
+    else {
+        return Err(Error::Unsupported(
+            "only landmark fingerprints can be searched in a store".to_string(),
+        ));
+    };
+    match options.mode {
+        FingerprintMode::Landmark => ranking::search(store, fingerprints, ranking_options),
+        FingerprintMode::Invariant => {
+            ranking::search_invariant(store, fingerprints, ranking_options)
+        }
+    }
+}
+
+// Indexes the folder in memory and runs every query against it
+pub fn search_in_memory(
+    path: &PathBuf,
+    options: IndexOptions,
+    query_files: &[PathBuf],
+    ranking_options: &RankingOptions,
+) -> Result<()> {
+    let store = index_in_memory(path, options)?;
+    println!("{} songs indexed in memory", store.song_count());
+    for query_file in query_files {
+        println!("{}:", query_file.display());
+        let ranking = search_store(&store, query_file, options, ranking_options)?;
+        print_ranking(&ranking, options.mode);
     }
     Ok(())
 }
 
-pub fn search(query_file: &PathBuf, database_path: &PathBuf, rank: usize) -> Result<()> {
-    let db_client = DbClient::new(database_path);
+pub fn build_index(database_path: &PathBuf, output: &Path) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    let index = MmapIndex::build(&db_client, output)?;
+    let header = index.header();
+    println!(
+        "{} songs, {} addresses, {} postings, {} bytes (SQLite index: {} bytes)",
+        index.song_count(),
+        header.address_count,
+        header.posting_count,
+        index.file_size(),
+        fs::metadata(database_path).with_path(database_path)?.len()
+    );
+    Ok(())
+}
+
+// Searches an index file made by `build_index`, with the options it was built with
+pub fn search_index(
+    query_file: &PathBuf,
+    index_path: &Path,
+    density: Option<usize>,
+    verify: bool,
+    ranking_options: &RankingOptions,
+) -> Result<()> {
+    let index = MmapIndex::open(index_path)?;
+    if verify {
+        index.verify()?;
+    }
+    let options = IndexOptions {
+        algorithm: Algorithm::Landmark,
+        mode: index.header().mode,
+        density: query_density(density, index.header().density),
+    };
+    let ranking = search_store(&index, query_file, options, ranking_options)?;
+    print_ranking(&ranking, options.mode);
+    Ok(())
+}
+
+// Commands other than `index` never create an index, a missing one is reported instead
+fn open_index(database_path: &PathBuf) -> Result<DbClient> {
+    if !database_path.exists() {
+        return Err(Error::NoIndex {
+            path: database_path.clone(),
+        });
+    }
+    DbClient::new(database_path)
+}
+
+fn read_sample(path: &PathBuf) -> Result<Sample> {
+    let mut sample = Sample::read_mp3(path)?;
+    Ok(sample.downsample(DOWNSAMPLE_FACTOR))
+}
+
+pub fn compute_fingerprints(
+    audio_file: &PathBuf,
+    options: IndexOptions,
+) -> Result<FingerprintFile> {
+    let IndexOptions {
+        algorithm,
+        mode,
+        density,
+    } = options;
+    let sample = read_sample(audio_file)?;
+    let config = FingerprintConfig::new(algorithm, mode, sample.sample_rate);
+    let fingerprints = match algorithm {
+        Algorithm::Landmark => Fingerprints::Landmark(
+            LandmarkFingerprinter {
+                mode,
+                density: density.map(DensityController::new),
+            }
+            .generate(&sample),
+        ),
+        Algorithm::Philips => Fingerprints::Philips(PhilipsFingerprinter.generate(&sample)),
+    };
+    let empty = match &fingerprints {
+        Fingerprints::Landmark(fingerprints) => fingerprints.is_empty(),
+        Fingerprints::Philips(sub_fingerprints) => sub_fingerprints.is_empty(),
+    };
+    if empty {
+        return Err(Error::InsufficientAudio {
+            path: audio_file.clone(),
+        });
+    }
+    Ok(FingerprintFile::new(config, fingerprints))
+}
 
-    let mut sample = Sample::read_mp3(query_file)?;
-    sample = sample.downsample(4);
+pub fn export_fingerprint(
+    audio_file: &PathBuf,
+    output: &PathBuf,
+    options: IndexOptions,
+    json: bool,
+) -> Result<()> {
+    compute_fingerprints(audio_file, options)?.save(output, json)
+}
 
-    let mut spectrogram = generate_spectrogram(&sample.sample, spectrogram::WindowSize::S1024, 512);
-    let peaks = filter_spectrogram(&mut spectrogram, sample.sample_rate);
+/*
+    Queries are sparser than the index unless a density is given: with QUERY_DENSITY_PERCENT of the
+    index's budget the strongest peaks of the query are still found in the index, and there are
+    fewer hashes to look up. An index keeping every peak is queried with every peak.
+*/
+fn query_density(density: Option<usize>, index_density: Option<usize>) -> Option<usize> {
+    density.or(index_density.map(|density| (density * QUERY_DENSITY_PERCENT / 100).max(1)))
+}
 
-    let fingerprints = generate_fingerprint(peaks);
+pub fn search(
+    query_file: &PathBuf,
+    database_path: &PathBuf,
+    density: Option<usize>,
+    ranking_options: &RankingOptions,
+) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    let options = IndexOptions {
+        algorithm: db_client.algorithm()?,
+        mode: db_client.fingerprint_mode()?,
+        density: query_density(density, db_client.density()?),
+    };
+    let query = compute_fingerprints(query_file, options)?;
+    search_fingerprints(&db_client, query, ranking_options)
+}
+
+// A song id, or else a title that only one song has
+fn find_song(db_client: &DbClient, song: &str) -> Result<i32> {
+    if let Ok(song_id) = song.parse::<i32>() {
+        db_client.get_song_data(song_id)?;
+        return Ok(song_id);
+    }
+    let song_ids = db_client
+        .songs()?
+        .into_iter()
+        .filter(|(_, data)| data.title.eq_ignore_ascii_case(song))
+        .map(|(song_id, _)| song_id)
+        .collect::<Vec<_>>();
+    match song_ids[..] {
+        [song_id] => Ok(song_id),
+        [] => Err(Error::SongTitleNotFound(song.to_string())),
+        _ => Err(Error::Unsupported(format!(
+            "songs {} are all titled \"{}\", pass an id instead",
+            song_ids
+                .iter()
+                .map(|song_id| song_id.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            song
+        ))),
+    }
+}
 
-    let ranking = db_client.search(fingerprints, rank)?;
+// Prints as JSON how one song scored against the query, see `ranking::explain`
+pub fn explain_search(
+    query_file: &PathBuf,
+    database_path: &PathBuf,
+    density: Option<usize>,
+    song: &str,
+) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    let options = IndexOptions {
+        algorithm: db_client.algorithm()?,
+        mode: db_client.fingerprint_mode()?,
+        density: query_density(density, db_client.density()?),
+    };
+    if options.algorithm != Algorithm::Landmark || options.mode != FingerprintMode::Landmark {
+        return Err(Error::Unsupported(
+            "only landmark indexes without --invariant can be explained".to_string(),
+        ));
+    }
+    let song_id = find_song(&db_client, song)?;
+    let Fingerprints::Landmark(fingerprints) =
+        compute_fingerprints(query_file, options)?.fingerprints
+    else {
+        unreachable!("landmark options give landmark fingerprints");
+    };
+
+    let explanation = ranking::explain(&db_client, &fingerprints, song_id)?;
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &explanation).map_err(io::Error::from)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+// Prints which songs the query plays and when, for queries holding several songs such as DJ mixes
+pub fn search_segments(
+    query_file: &PathBuf,
+    database_path: &PathBuf,
+    density: Option<usize>,
+    min_confidence: f32,
+) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    let options = IndexOptions {
+        algorithm: db_client.algorithm()?,
+        mode: db_client.fingerprint_mode()?,
+        density: query_density(density, db_client.density()?),
+    };
+    if options.algorithm != Algorithm::Landmark || options.mode != FingerprintMode::Landmark {
+        return Err(Error::Unsupported(
+            "only landmark indexes without --invariant can be segmented".to_string(),
+        ));
+    }
+    let Fingerprints::Landmark(fingerprints) =
+        compute_fingerprints(query_file, options)?.fingerprints
+    else {
+        unreachable!("landmark options give landmark fingerprints");
+    };
+
+    let timeline = segment::segment(&db_client, &fingerprints, min_confidence)?;
+    if timeline.is_empty() {
+        println!("No match");
+    }
+    for segment in timeline {
+        println!(
+            "{}-{} {} (song at {}, score: {}, confidence: {:.2})",
+            format_time(segment.query_start),
+            format_time(segment.query_end),
+            segment.data.display_name(),
+            format_time(segment.query_start + segment.reference_offset),
+            segment.score,
+            segment.confidence
+        );
+    }
+    Ok(())
+}
+
+/*
+    Searches every audio file of a folder, or every file listed one per line in a text file, and
+    writes one CSV row or JSON line per result as soon as it is known.
+*/
+pub fn search_batch(
+    input: &Path,
+    database_path: &PathBuf,
+    density: Option<usize>,
+    ranking_options: &RankingOptions,
+    format: BatchFormat,
+    threads: usize,
+) -> Result<()> {
+    let query_files = if input.is_dir() {
+        audio_files(input)?
+    } else {
+        fs::read_to_string(input)
+            .with_path(input)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect()
+    };
+
+    let db_client = open_index(database_path)?;
+    let options = IndexOptions {
+        algorithm: db_client.algorithm()?,
+        mode: db_client.fingerprint_mode()?,
+        density: query_density(density, db_client.density()?),
+    };
+    let mut stdout = io::stdout().lock();
+    batch::write_header(&mut stdout, format)?;
+    batch::search_many(
+        &db_client,
+        &query_files,
+        |query| compute_fingerprints(query, options),
+        ranking_options,
+        threads,
+        |result| {
+            batch::write_result(&mut stdout, format, &result)?;
+            stdout.flush()?;
+            Ok(())
+        },
+    )
+}
+
+/*
+    Identifies a live feed of raw 16-bit little-endian PCM, interleaved when there are several
+    channels. Every start and stop of a song is printed as a JSON line as soon as it is known.
+*/
+pub fn monitor_stream(
+    database_path: &PathBuf,
+    input: &mut dyn Read,
+    sample_rate: usize,
+    channels: usize,
+    options: MonitorOptions,
+) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    // The index records the rate after downsampling, which the feed goes through as well
+    if let Some(indexed) = db_client.sample_rate()?
+        && sample_rate / DOWNSAMPLE_FACTOR != indexed
+    {
+        return Err(Error::IncompatibleIndex(format!(
+            "the feed is sampled at {} Hz but the index was built from audio at {} Hz",
+            sample_rate,
+            indexed * DOWNSAMPLE_FACTOR
+        )));
+    }
+    let index = IndexOptions {
+        algorithm: db_client.algorithm()?,
+        mode: db_client.fingerprint_mode()?,
+        density: query_density(None, db_client.density()?),
+    };
+    let mut monitor = Monitor::new(&db_client, index, sample_rate, options)?;
+
+    let mut stdout = io::stdout().lock();
+    let mut print = |events: Vec<monitor::MonitorEvent>| -> Result<()> {
+        for event in events {
+            serde_json::to_writer(&mut stdout, &event).map_err(io::Error::from)?;
+            writeln!(stdout)?;
+        }
+        stdout.flush()?;
+        Ok(())
+    };
+
+    let frame_size = 2 * channels.max(1);
+    let mut bytes = vec![0u8; 64 * 1024];
+    // Bytes of a frame split between two reads
+    let mut leftover = 0;
+    loop {
+        let read = match input.read(&mut bytes[leftover..]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let available = leftover + read;
+        let frames = &bytes[..available - available % frame_size];
+        let samples = frames
+            .chunks_exact(frame_size)
+            .map(|frame| {
+                let sum: f32 = frame
+                    .chunks_exact(2)
+                    .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32)
+                    .sum();
+                sum / channels.max(1) as f32
+            })
+            .collect::<Vec<_>>();
+        print(monitor.push(&samples)?)?;
+
+        leftover = available % frame_size;
+        bytes.copy_within(available - leftover..available, 0);
+    }
+    print(monitor.finish())
+}
+
+pub fn search_fingerprint_file(
+    fingerprint_file: &PathBuf,
+    database_path: &PathBuf,
+    ranking_options: &RankingOptions,
+) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    let query = FingerprintFile::load(fingerprint_file)?;
+    search_fingerprints(&db_client, query, ranking_options)
+}
+
+fn search_fingerprints(
+    db_client: &DbClient,
+    query: FingerprintFile,
+    ranking_options: &RankingOptions,
+) -> Result<()> {
+    let algorithm = db_client.algorithm()?;
+    let mode = db_client.fingerprint_mode()?;
+    // Indexes made before the sample rate was recorded can't tell, the query's is taken on trust
+    let sample_rate = match db_client.sample_rate()? {
+        Some(sample_rate) => sample_rate,
+        None => query.config.sample_rate as usize,
+    };
+    query
+        .config
+        .check_compatible(&FingerprintConfig::new(algorithm, mode, sample_rate))?;
+
+    let fingerprints = match query.fingerprints {
+        Fingerprints::Philips(sub_fingerprints) => {
+            ranking_options.check_landmark_only("Philips")?;
+            let query_end = sub_fingerprints
+                .iter()
+                .map(|s| s.frame as i64 + 1)
+                .max()
+                .unwrap_or(0);
+            let ranking = db_client.search_philips(
+                sub_fingerprints,
+                ranking_options.rank,
+                ranking_options.min_confidence,
+            )?;
+            if ranking.is_empty() {
+                println!("No match");
+            }
+            for (index, data) in ranking.iter().enumerate() {
+                let seconds =
+                    |frames: i64| (frames * philips::HOP_SIZE as i64) as f32 / sample_rate as f32;
+                println!(
+                    "{}. {} (bit error rate: {:.3}, confidence: {:.2}, played {}-{}, query {}-{})",
+                    index + 1,
+                    data.data.display_name(),
+                    data.bit_error_rate,
+                    data.confidence,
+                    format_time(seconds(data.frame_offset)),
+                    format_time(seconds(data.frame_offset + query_end)),
+                    format_time(0.0),
+                    format_time(seconds(query_end))
+                );
+            }
+            return Ok(());
+        }
+        Fingerprints::Landmark(fingerprints) => fingerprints,
+    };
+
+    let ranking = match mode {
+        FingerprintMode::Landmark => ranking::search(db_client, fingerprints, ranking_options)?,
+        FingerprintMode::Invariant => {
+            ranking::search_invariant(db_client, fingerprints, ranking_options)?
+        }
+    };
+    print_ranking(&ranking, mode);
+    Ok(())
+}
+
+// m:ss, times before the start of the reference are shown as 0:00
+fn format_time(seconds: f32) -> String {
+    let seconds = seconds.max(0.0).round() as u32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn print_ranking(ranking: &[RankingData], mode: FingerprintMode) {
+    // Everything was below the minimum confidence
+    if ranking.is_empty() {
+        println!("No match");
+    }
     for (index, data) in ranking.iter().enumerate() {
-        println!("{}. {} (score: {})", index + 1, data.data.title, data.score);
+        let position = &data.position;
+        let played = format!(
+            "played {}-{}, query {}-{}",
+            format_time(position.reference_start),
+            format_time(position.reference_end),
+            format_time(position.query_start),
+            format_time(position.query_end)
+        );
+        match mode {
+            FingerprintMode::Landmark => {
+                println!(
+                    "{}. {} (score: {}, confidence: {:.2}, {})",
+                    index + 1,
+                    data.data.display_name(),
+                    data.score,
+                    data.confidence,
+                    played
+                )
+            }
+            FingerprintMode::Invariant => println!(
+                "{}. {} (score: {}, confidence: {:.2}, speed: {:.3}x, {})",
+                index + 1,
+                data.data.display_name(),
+                data.score,
+                data.confidence,
+                data.speed,
+                played
+            ),
+        }
+    }
+}
+
+pub fn remove_songs(database_path: &PathBuf, song_ids: &[i32]) -> Result<()> {
+    let mut db_client = open_index(database_path)?;
+    for (song_id, song) in db_client.remove_songs(song_ids)? {
+        println!("Removed {}. {}", song_id, song.display_name());
+    }
+    Ok(())
+}
+
+// Fields left as None keep their current value
+pub fn rename_song(
+    database_path: &PathBuf,
+    song_id: i32,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    let mut song = db_client.get_song_data(song_id)?;
+    if let Some(title) = title {
+        song.title = title;
+    }
+    song.artist = artist.or(song.artist);
+    song.album = album.or(song.album);
+    db_client.update_song(song_id, &song)?;
+    println!("{}. {}", song_id, song.display_name());
+    Ok(())
+}
+
+pub fn list_songs(
+    database_path: &PathBuf,
+    filter: Option<&str>,
+    limit: usize,
+    offset: usize,
+) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    for (song_id, song) in db_client.list_songs(filter, limit, offset)? {
+        match &song.path {
+            Some(path) => println!("{}. {} ({})", song_id, song.display_name(), path),
+            None => println!("{}. {}", song_id, song.display_name()),
+        }
     }
     Ok(())
 }
+
+pub fn print_duplicates(database_path: &PathBuf, min_overlap: f32) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    let groups = db_client.find_duplicates(min_overlap)?;
+    if groups.is_empty() {
+        println!("No duplicates");
+    }
+    for (index, group) in groups.iter().enumerate() {
+        println!("Group {}:", index + 1);
+        for (song_id, song) in &group.songs {
+            match &song.path {
+                Some(path) => println!("  {}. {} ({})", song_id, song.display_name(), path),
+                None => println!("  {}. {}", song_id, song.display_name()),
+            }
+        }
+        for pair in &group.pairs {
+            println!(
+                "  {} is in {} at {} (overlap: {:.2})",
+                pair.song_id,
+                pair.other_id,
+                format_time(pair.offset),
+                pair.overlap
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn print_stats(database_path: &PathBuf) -> Result<()> {
+    let db_client = open_index(database_path)?;
+    let (stats, unit) = match db_client.algorithm()? {
+        Algorithm::Landmark => (db_client.stats()?, "hashes"),
+        Algorithm::Philips => (db_client.sub_fingerprint_stats()?, "sub-fingerprints"),
+    };
+    for song in &stats {
+        println!(
+            "{}. {} ({} {}, {:.1}s, {:.1} {}/s)",
+            song.song_id,
+            song.title,
+            song.hashes,
+            unit,
+            song.duration_ms as f32 / 1000.0,
+            song.hashes_per_second(),
+            unit
+        );
+    }
+    if !stats.is_empty() {
+        let hashes = stats.iter().map(|s| s.hashes).sum::<usize>();
+        let duration_ms = stats.iter().map(|s| s.duration_ms as u64).sum::<u64>();
+        println!(
+            "Total: {} songs, {} {}, {:.1} {}/s",
+            stats.len(),
+            hashes,
+            unit,
+            hashes as f32 * 1000.0 / duration_ms.max(1) as f32,
+            unit
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_density() {
+        assert_eq!(query_density(None, Some(60)), Some(45));
+        assert_eq!(query_density(None, Some(1)), Some(1));
+        assert_eq!(query_density(Some(80), Some(60)), Some(80));
+        assert_eq!(query_density(None, None), None);
+    }
+
+    #[test]
+    fn test_find_song_by_id_or_title() {
+        let mut db_client = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for title in ["Intro", "Outro", "outro", "42"] {
+            db_client
+                .insert_song(&SongData {
+                    title: title.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        assert_eq!(find_song(&db_client, "2").unwrap(), 2);
+        assert_eq!(find_song(&db_client, "intro").unwrap(), 1);
+        // Numbers are ids, a song titled with one has to be found by its own id
+        assert!(matches!(
+            find_song(&db_client, "42"),
+            Err(Error::SongNotFound(42))
+        ));
+        assert!(matches!(
+            find_song(&db_client, "Bridge"),
+            Err(Error::SongTitleNotFound(title)) if title == "Bridge"
+        ));
+        let Err(Error::Unsupported(message)) = find_song(&db_client, "OUTRO") else {
+            panic!("an ambiguous title must be refused");
+        };
+        assert!(message.contains("songs 2, 3 are all titled"));
+    }
+
+    #[test]
+    fn test_monitor_needs_the_indexed_sample_rate() {
+        let database_path =
+            std::env::temp_dir().join(format!("shezem-monitor-{}.db", std::process::id()));
+        let _ = fs::remove_file(&database_path);
+        DbClient::new(&database_path)
+            .unwrap()
+            .set_sample_rate(11025)
+            .unwrap();
+
+        let monitor = |sample_rate| {
+            monitor_stream(
+                &database_path,
+                &mut io::empty(),
+                sample_rate,
+                2,
+                MonitorOptions::default(),
+            )
+        };
+        assert!(matches!(monitor(48000), Err(Error::IncompatibleIndex(_))));
+        monitor(44100).unwrap();
+
+        fs::remove_file(&database_path).unwrap();
+    }
+
+    // MPEG-1 layer III frames at 128 kbps, 44.1 kHz and mono, all zero so they decode to silence
+    fn silent_mp3(frames: usize) -> Vec<u8> {
+        let mut frame = vec![0; 417];
+        frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0xc0]);
+        frame.repeat(frames)
+    }
+
+    fn fingerprint_count(db_client: &mut DbClient, song_id: i32) -> i64 {
+        db_client
+            .get_conn()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM fingerprints WHERE songID = ?",
+                [song_id],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_index_folder_is_incremental() {
+        let folder = std::env::temp_dir().join(format!("shezem-index-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let database_path = folder.join("index.db");
+        let (a, b) = (folder.join("a.mp3"), folder.join("b.mp3"));
+        fs::write(&a, silent_mp3(100)).unwrap();
+        fs::write(&b, silent_mp3(120)).unwrap();
+        let options = IndexOptions::default();
+
+        // A song indexed before paths were recorded keeps its id
+        let legacy_id = DbClient::new(&database_path)
+            .unwrap()
+            .register_song(&SongData {
+                title: "a".to_string(),
+                ..Default::default()
+            })
+            .unwrap() as i32;
+        let summary = index_folder(&folder, &database_path, options, false).unwrap();
+        assert_eq!((summary.added, summary.updated), (1, 1));
+        let mut db_client = DbClient::new(&database_path).unwrap();
+        let a_path = fs::canonicalize(&a).unwrap().to_string_lossy().to_string();
+        let (song_id, _) = db_client.find_song_by_path(&a_path).unwrap().unwrap();
+        assert_eq!(song_id, legacy_id);
+
+        let summary = index_folder(&folder, &database_path, options, false).unwrap();
+        assert_eq!(
+            summary,
+            IndexSummary {
+                unchanged: 2,
+                ..Default::default()
+            }
+        );
+
+        // A fingerprint the new content can't produce shows the old ones are dropped
+        let mut tx = db_client.get_conn().unwrap();
+        DbClient::register_fingerprint(
+            &FingerprintData {
+                fingerprint: fingerprint::Fingerprint {
+                    address: u32::MAX,
+                    anchor_address: 0,
+                    anchor_time: 0,
+                },
+                song_id,
+            },
+            &mut tx,
+        )
+        .unwrap();
+        tx.commit().unwrap();
+        fs::write(&a, silent_mp3(150)).unwrap();
+        let summary = index_folder(&folder, &database_path, options, false).unwrap();
+        assert_eq!((summary.updated, summary.unchanged), (1, 1));
+        let (updated_id, song) = db_client.find_song_by_path(&a_path).unwrap().unwrap();
+        assert_eq!(updated_id, song_id);
+        assert_eq!(song.file_size, Some(150 * 417));
+        assert_eq!(fingerprint_count(&mut db_client, song_id), 0);
+
+        fs::remove_file(&b).unwrap();
+        let summary = index_folder(&folder, &database_path, options, true).unwrap();
+        assert_eq!((summary.removed, summary.unchanged), (1, 1));
+        assert_eq!(db_client.song_count().unwrap(), 1);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_index_folder_keeps_fingerprints_and_settings_together() {
+        let folder = std::env::temp_dir().join(format!("shezem-settings-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let database_path = folder.join("index.db");
+        let a = folder.join("a.mp3");
+        fs::write(&a, silent_mp3(100)).unwrap();
+        index_folder(&folder, &database_path, IndexOptions::default(), false).unwrap();
+
+        // Silence has no fingerprints, this one stands in for those of the first run
+        let mut db_client = DbClient::new(&database_path).unwrap();
+        let a_path = fs::canonicalize(&a).unwrap().to_string_lossy().to_string();
+        let (song_id, _) = db_client.find_song_by_path(&a_path).unwrap().unwrap();
+        let mut tx = db_client.get_conn().unwrap();
+        DbClient::register_fingerprint(
+            &FingerprintData {
+                fingerprint: fingerprint::Fingerprint {
+                    address: u32::MAX,
+                    anchor_address: 0,
+                    anchor_time: 0,
+                },
+                song_id,
+            },
+            &mut tx,
+        )
+        .unwrap();
+        tx.execute_batch(
+            "CREATE TRIGGER read_only_settings BEFORE INSERT ON settings
+            BEGIN SELECT RAISE(ABORT, 'read-only settings'); END",
+        )
+        .unwrap();
+        tx.commit().unwrap();
+
+        // A new density fingerprints the song again, and fails when the density is written
+        let options = IndexOptions {
+            density: Some(30),
+            ..Default::default()
+        };
+        assert!(index_folder(&folder, &database_path, options, false).is_err());
+        assert_eq!(fingerprint_count(&mut db_client, song_id), 1);
+        assert_eq!(db_client.density().unwrap(), None);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
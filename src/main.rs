@@ -1,7 +1,17 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use shezem_rs::{index_folder, search};
-use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum, builder::RangedU64ValueParser};
+use shezem_rs::{
+    IndexOptions, Result,
+    batch::BatchFormat,
+    build_index, explain_search, export_fingerprint,
+    fingerprint::{Algorithm, FingerprintMode},
+    index_folder, list_songs,
+    monitor::MonitorOptions,
+    monitor_stream, print_duplicates, print_stats,
+    ranking::{HistogramScorer, LisScorer, RankingOptions, Scorer},
+    remove_songs, rename_song, search, search_batch, search_fingerprint_file, search_in_memory,
+    search_index, search_segments,
+};
+use std::{path::PathBuf, process::ExitCode};
 
 #[derive(Parser)]
 #[command(
@@ -20,45 +30,552 @@ enum Commands {
     Index {
         #[arg(value_name = "PATH")]
         path: PathBuf,
+
+        /// Hash frequency and time ratios so that sped up or slowed down copies still match
+        #[arg(long)]
+        invariant: bool,
+
+        /// Use Haitsma-Kalker band energy sub-fingerprints, more robust to heavy EQ and compression
+        #[arg(long, conflicts_with = "invariant")]
+        philips: bool,
+
+        /// Keep at most this many of the strongest peaks per second
+        #[arg(long, value_name = "PEAKS_PER_SECOND", value_parser = positive_parser(), conflicts_with = "philips")]
+        density: Option<usize>,
+
+        /// Remove songs whose files no longer exist in the folder
+        #[arg(long)]
+        prune: bool,
+
+        /// Keep the index in memory instead of writing it to the folder, use with --query
+        #[arg(long, requires = "queries", conflicts_with_all = ["philips", "prune"])]
+        in_memory: bool,
+
+        /// Audio files to search for once the in-memory index is built
+        #[arg(long = "query", value_name = "AUDIO_FILE", num_args = 1.., requires = "in_memory")]
+        queries: Vec<PathBuf>,
+
+        #[arg(short, long, default_value = "10")]
+        rank: usize,
+
+        /// How the matches of the queries are scored
+        #[arg(long, value_enum, default_value = "lis", requires = "in_memory")]
+        scorer: ScorerKind,
+
+        /// Report no match instead of songs below this confidence, between 0 and 1
+        #[arg(long, default_value = "0", value_parser = ratio, requires = "in_memory")]
+        min_confidence: f32,
     },
 
     Search {
+        #[arg(value_name = "AUDIO_FILE", required_unless_present = "fingerprint")]
+        query_file: Option<PathBuf>,
+
+        /// Query with a fingerprint file made by the `fingerprint` command instead of audio
+        #[arg(long, value_name = "FINGERPRINT_FILE", conflicts_with = "query_file")]
+        fingerprint: Option<PathBuf>,
+
+        #[arg(short, long, value_name = "DB_PATH", required_unless_present = "index")]
+        path: Option<PathBuf>,
+
+        /// Search an index file made by `build-index` instead of the folder's SQLite index
+        #[arg(long, value_name = "INDEX_FILE", conflicts_with_all = ["path", "fingerprint"])]
+        index: Option<PathBuf>,
+
+        /// Check the whole index file against its checksum before searching, which reads all of it
+        #[arg(long, requires = "index", conflicts_with = "path")]
+        verify: bool,
+
+        #[arg(short, long, default_value = "10")]
+        rank: usize,
+
+        /// Peak budget of the query, defaults to 3/4 of the one the index was built with
+        #[arg(long, value_name = "PEAKS_PER_SECOND", value_parser = positive_parser())]
+        density: Option<usize>,
+
+        /// How matches are scored, invariant indexes always vote on time offsets
+        #[arg(long, value_enum, default_value = "lis")]
+        scorer: ScorerKind,
+
+        /// Report no match instead of songs below this confidence, between 0 and 1
+        #[arg(long, default_value = "0", value_parser = ratio)]
+        min_confidence: f32,
+
+        /// Skip hashes stored more often than this, they are found in most songs and slow searches down
+        #[arg(long, value_name = "ROWS")]
+        max_postings: Option<usize>,
+
+        /// Only score the songs with the most matches, faster on large catalogues at some cost in recall
+        #[arg(long, value_name = "SONGS")]
+        max_candidates: Option<usize>,
+
+        /// List every song the query plays with its time in the query, for DJ mixes and radio recordings
+        #[arg(long, conflicts_with_all = ["fingerprint", "index", "scorer", "rank", "max_postings", "max_candidates"])]
+        segment: bool,
+
+        /// Print as JSON how this song, a title or an id, scored against the query
+        #[arg(long, value_name = "SONG", conflicts_with_all = ["fingerprint", "index", "scorer", "rank", "segment", "max_postings", "max_candidates"])]
+        explain: Option<String>,
+    },
+
+    Fingerprint {
         #[arg(value_name = "AUDIO_FILE")]
-        query_file: PathBuf,
+        audio_file: PathBuf,
+
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: PathBuf,
+
+        /// Write the readable JSON form instead of the compact binary one
+        #[arg(long)]
+        json: bool,
+
+        /// Must match the options the index was built with
+        #[arg(long)]
+        invariant: bool,
+
+        #[arg(long, conflicts_with = "invariant")]
+        philips: bool,
+
+        #[arg(long, value_name = "PEAKS_PER_SECOND", value_parser = positive_parser(), conflicts_with = "philips")]
+        density: Option<usize>,
+    },
+
+    /// Search many audio files in one process, writing CSV or JSON lines with the time every query took
+    SearchBatch {
+        /// A folder of audio files, or a text file listing one audio file per line
+        #[arg(value_name = "DIR_OR_LIST")]
+        input: PathBuf,
 
         #[arg(short, long, value_name = "DB_PATH")]
         path: PathBuf,
 
-        #[arg(short, long, default_value = "10")]
+        #[arg(short, long, default_value = "1")]
         rank: usize,
+
+        /// Peak budget of the queries, defaults to 3/4 of the one the index was built with
+        #[arg(long, value_name = "PEAKS_PER_SECOND", value_parser = positive_parser())]
+        density: Option<usize>,
+
+        #[arg(long, value_enum, default_value = "lis")]
+        scorer: ScorerKind,
+
+        #[arg(long, default_value = "0", value_parser = ratio)]
+        min_confidence: f32,
+
+        /// Skip hashes stored more often than this, they are found in most songs and slow searches down
+        #[arg(long, value_name = "ROWS")]
+        max_postings: Option<usize>,
+
+        /// Only score the songs with the most matches, faster on large catalogues at some cost in recall
+        #[arg(long, value_name = "SONGS")]
+        max_candidates: Option<usize>,
+
+        #[arg(long, value_enum, default_value = "csv")]
+        format: FormatKind,
+
+        /// Queries fingerprinted at the same time, defaults to the number of CPUs
+        #[arg(long)]
+        threads: Option<usize>,
+    },
+
+    /// Identify what plays on a live feed of raw 16-bit little-endian PCM read from stdin, writing one JSON event per line
+    Monitor {
+        #[arg(short, long, value_name = "DB_PATH")]
+        path: PathBuf,
+
+        /// Must be the sample rate of the indexed files
+        #[arg(long, default_value = "44100", value_parser = positive_parser())]
+        sample_rate: usize,
+
+        #[arg(long, default_value = "2", value_parser = positive_parser())]
+        channels: usize,
+
+        /// Seconds of audio searched at a time
+        #[arg(long, default_value = "10", value_parser = seconds_parser())]
+        window: u32,
+
+        /// Seconds between two searches
+        #[arg(long, default_value = "2", value_parser = seconds_parser())]
+        interval: u32,
+
+        /// Confidence a song needs in two searches in a row to start
+        #[arg(long, default_value = "0.5", value_parser = ratio)]
+        start_confidence: f32,
+
+        /// A song stops after three searches in a row below this confidence
+        #[arg(long, default_value = "0.2", value_parser = ratio)]
+        stop_confidence: f32,
+    },
+
+    /// Convert the folder's SQLite index into a compact read-only index file for faster searches
+    BuildIndex {
+        #[arg(short, long, value_name = "DB_PATH")]
+        path: PathBuf,
+
+        /// Defaults to index.shzi next to the SQLite index
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<PathBuf>,
+    },
+
+    /// Show how many hashes per second every indexed song produced
+    Stats {
+        #[arg(short, long, value_name = "DB_PATH")]
+        path: PathBuf,
+    },
+
+    /// Find songs indexed more than once, under other names or inside longer tracks
+    Dedupe {
+        #[arg(short, long, value_name = "DB_PATH")]
+        path: PathBuf,
+
+        /// Share of the shorter song that has to be found in the other one, between 0 and 1
+        #[arg(long, default_value = "0.5", value_parser = ratio)]
+        min_overlap: f32,
+    },
+
+    /// Delete songs and their fingerprints from the index
+    Remove {
+        #[arg(value_name = "SONG_ID", required = true)]
+        song_ids: Vec<i32>,
+
+        #[arg(short, long, value_name = "DB_PATH")]
+        path: PathBuf,
+    },
+
+    /// Correct the metadata of an indexed song
+    Rename {
+        #[arg(value_name = "SONG_ID")]
+        song_id: i32,
+
+        #[arg(short, long, value_name = "DB_PATH")]
+        path: PathBuf,
+
+        #[arg(long, required_unless_present_any = ["artist", "album"])]
+        title: Option<String>,
+
+        #[arg(long)]
+        artist: Option<String>,
+
+        #[arg(long)]
+        album: Option<String>,
+    },
+
+    /// List indexed songs with their ids
+    List {
+        #[arg(short, long, value_name = "DB_PATH")]
+        path: PathBuf,
+
+        /// Only show songs whose title, artist, album or path contains this text
+        #[arg(short, long)]
+        filter: Option<String>,
+
+        #[arg(long, default_value = "50")]
+        limit: usize,
+
+        /// Number of songs to skip, for paging through large indexes
+        #[arg(long, default_value = "0")]
+        offset: usize,
     },
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum ScorerKind {
+    /// Longest run of matches in the same order as the query
+    Lis,
+    /// Matches agreeing on the time offset between the query and the song
+    Histogram,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatKind {
+    /// One row per result
+    Csv,
+    /// One JSON object per query
+    Json,
+}
+
+impl ScorerKind {
+    fn scorer(self) -> Box<dyn Scorer> {
+        match self {
+            ScorerKind::Lis => Box::new(LisScorer),
+            ScorerKind::Histogram => Box::new(HistogramScorer::default()),
+        }
+    }
+}
+
 const DEFAULT_DB_PATH: &str = "db.db3";
 const DEFAULT_FOLDER_DB_PATH: &str = ".db";
+const DEFAULT_INDEX_FILE: &str = "index.shzi";
+
+// Densities, sample rates and channel counts, where 0 means nothing
+fn positive_parser() -> RangedU64ValueParser<usize> {
+    RangedU64ValueParser::new().range(1..)
+}
+
+// Confidences and overlaps, NaN and values outside 0..=1 would keep everything or nothing
+fn ratio(value: &str) -> Result<f32, String> {
+    let ratio = value
+        .parse::<f32>()
+        .map_err(|err| format!("{} is not a number: {}", value, err))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("{} is not between 0 and 1", value));
+    }
+    Ok(ratio)
+}
+
+// Seconds that still fit in a u32 once converted to milliseconds
+fn seconds_parser() -> RangedU64ValueParser<u32> {
+    RangedU64ValueParser::new().range(1..=(u32::MAX / 1000) as u64)
+}
 
-fn main() -> Result<()> {
+fn index_options(invariant: bool, philips: bool, density: Option<usize>) -> IndexOptions {
+    let algorithm = if philips {
+        Algorithm::Philips
+    } else {
+        Algorithm::Landmark
+    };
+    let mode = if invariant {
+        FingerprintMode::Invariant
+    } else {
+        FingerprintMode::Landmark
+    };
+    IndexOptions {
+        algorithm,
+        mode,
+        density,
+    }
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
 
+fn run(cli: &Cli) -> Result<()> {
     match &cli.command {
-        Commands::Index { path } => {
-            let db_folder_path = path.join(DEFAULT_FOLDER_DB_PATH);
-            if !db_folder_path.exists() {
-                std::fs::create_dir_all(&db_folder_path)?;
+        Commands::Index {
+            path,
+            invariant,
+            philips,
+            density,
+            prune,
+            in_memory,
+            queries,
+            rank,
+            scorer,
+            min_confidence,
+        } => {
+            let options = index_options(*invariant, *philips, *density);
+            if *in_memory {
+                let scorer = scorer.scorer();
+                let ranking_options = RankingOptions {
+                    rank: *rank,
+                    scorer: scorer.as_ref(),
+                    min_confidence: *min_confidence,
+                    ..Default::default()
+                };
+                search_in_memory(path, options, queries, &ranking_options)?;
+                return Ok(());
             }
 
-            let default_db_path = db_folder_path.join(DEFAULT_DB_PATH);
-            index_folder(path, &default_db_path)?;
+            let default_db_path = path.join(DEFAULT_FOLDER_DB_PATH).join(DEFAULT_DB_PATH);
+            let summary = index_folder(path, &default_db_path, options, *prune)?;
+            println!(
+                "{} added, {} updated, {} unchanged, {} removed",
+                summary.added, summary.updated, summary.unchanged, summary.removed
+            );
             Ok(())
         }
 
         Commands::Search {
             query_file,
+            fingerprint,
+            path,
+            index,
+            verify,
+            rank,
+            density,
+            scorer,
+            min_confidence,
+            max_postings,
+            max_candidates,
+            segment,
+            explain,
+        } => {
+            let scorer = scorer.scorer();
+            let ranking_options = RankingOptions {
+                rank: *rank,
+                scorer: scorer.as_ref(),
+                min_confidence: *min_confidence,
+                max_postings: *max_postings,
+                max_candidates: *max_candidates,
+            };
+            if let Some(index) = index {
+                let query_file = query_file.as_ref().expect("clap requires a query file");
+                search_index(query_file, index, *density, *verify, &ranking_options)?;
+                return Ok(());
+            }
+
+            let path = path
+                .as_ref()
+                .expect("clap requires a path without an index");
+            let default_db_path = path.join(DEFAULT_FOLDER_DB_PATH).join(DEFAULT_DB_PATH);
+            match (query_file, fingerprint) {
+                (_, Some(fingerprint)) => {
+                    search_fingerprint_file(fingerprint, &default_db_path, &ranking_options)?
+                }
+                (Some(query_file), None) if let Some(song) = explain => {
+                    explain_search(query_file, &default_db_path, *density, song)?
+                }
+                (Some(query_file), None) if *segment => {
+                    search_segments(query_file, &default_db_path, *density, *min_confidence)?
+                }
+                (Some(query_file), None) => {
+                    search(query_file, &default_db_path, *density, &ranking_options)?
+                }
+                (None, None) => unreachable!("clap requires one of them"),
+            }
+            Ok(())
+        }
+
+        Commands::Fingerprint {
+            audio_file,
+            output,
+            json,
+            invariant,
+            philips,
+            density,
+        } => {
+            let options = index_options(*invariant, *philips, *density);
+            export_fingerprint(audio_file, output, options, *json)?;
+            Ok(())
+        }
+
+        Commands::SearchBatch {
+            input,
             path,
             rank,
+            density,
+            scorer,
+            min_confidence,
+            max_postings,
+            max_candidates,
+            format,
+            threads,
+        } => {
+            let default_db_path = path.join(DEFAULT_FOLDER_DB_PATH).join(DEFAULT_DB_PATH);
+            let scorer = scorer.scorer();
+            let ranking_options = RankingOptions {
+                rank: *rank,
+                scorer: scorer.as_ref(),
+                min_confidence: *min_confidence,
+                max_postings: *max_postings,
+                max_candidates: *max_candidates,
+            };
+            let format = match format {
+                FormatKind::Csv => BatchFormat::Csv,
+                FormatKind::Json => BatchFormat::JsonLines,
+            };
+            let threads = threads.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |threads| threads.get())
+            });
+            search_batch(
+                input,
+                &default_db_path,
+                *density,
+                &ranking_options,
+                format,
+                threads,
+            )?;
+            Ok(())
+        }
+
+        Commands::Monitor {
+            path,
+            sample_rate,
+            channels,
+            window,
+            interval,
+            start_confidence,
+            stop_confidence,
+        } => {
+            let default_db_path = path.join(DEFAULT_FOLDER_DB_PATH).join(DEFAULT_DB_PATH);
+            let options = MonitorOptions {
+                window_ms: window * 1000,
+                interval_ms: interval * 1000,
+                start_confidence: *start_confidence,
+                stop_confidence: *stop_confidence,
+                ..Default::default()
+            };
+            monitor_stream(
+                &default_db_path,
+                &mut std::io::stdin().lock(),
+                *sample_rate,
+                *channels,
+                options,
+            )?;
+            Ok(())
+        }
+
+        Commands::BuildIndex { path, output } => {
+            let db_folder_path = path.join(DEFAULT_FOLDER_DB_PATH);
+            let output = output
+                .clone()
+                .unwrap_or_else(|| db_folder_path.join(DEFAULT_INDEX_FILE));
+            build_index(&db_folder_path.join(DEFAULT_DB_PATH), &output)?;
+            Ok(())
+        }
+
+        Commands::Dedupe { path, min_overlap } => {
+            let default_db_path = path.join(DEFAULT_FOLDER_DB_PATH).join(DEFAULT_DB_PATH);
+            print_duplicates(&default_db_path, *min_overlap)?;
+            Ok(())
+        }
+
+        Commands::Stats { path } => {
+            let default_db_path = path.join(DEFAULT_FOLDER_DB_PATH).join(DEFAULT_DB_PATH);
+            print_stats(&default_db_path)?;
+            Ok(())
+        }
+
+        Commands::Remove { song_ids, path } => {
+            let default_db_path = path.join(DEFAULT_FOLDER_DB_PATH).join(DEFAULT_DB_PATH);
+            remove_songs(&default_db_path, song_ids)?;
+            Ok(())
+        }
+
+        Commands::Rename {
+            song_id,
+            path,
+            title,
+            artist,
+            album,
+        } => {
+            let default_db_path = path.join(DEFAULT_FOLDER_DB_PATH).join(DEFAULT_DB_PATH);
+            rename_song(
+                &default_db_path,
+                *song_id,
+                title.clone(),
+                artist.clone(),
+                album.clone(),
+            )?;
+            Ok(())
+        }
+
+        Commands::List {
+            path,
+            filter,
+            limit,
+            offset,
         } => {
             let default_db_path = path.join(DEFAULT_FOLDER_DB_PATH).join(DEFAULT_DB_PATH);
-            search(query_file, &default_db_path, *rank)?;
+            list_songs(&default_db_path, filter.as_deref(), *limit, *offset)?;
             Ok(())
         }
     }
@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    db::{SongData, SongStats},
+    error::{Error, Result},
+    fingerprint::{Fingerprint, FingerprintData},
+    store::{FingerprintStore, posting_order, unique_sorted},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Posting {
+    anchor_address: u32,
+    anchor_time: u32,
+    song_id: i32,
+}
+
+/*
+    Fingerprint store kept entirely in memory, for tests and short-lived jobs that don't need an
+    index on disk. Postings are grouped by address like the SQLite hash index, and every song keeps
+    the set of its fingerprints so that inserting a duplicate is a no-op, as with `INSERT OR IGNORE`.
+*/
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    songs: BTreeMap<i32, SongData>,
+    fingerprints: HashMap<i32, HashSet<Fingerprint>>,
+    postings: HashMap<u32, Vec<Posting>>,
+    last_song_id: i32,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn song_count(&self) -> usize {
+        self.songs.len()
+    }
+}
+
+impl FingerprintStore for MemoryStore {
+    fn insert_song(&mut self, song_data: &SongData) -> Result<i32> {
+        // Ids start at 1 and are never reused, like SQLite's AUTOINCREMENT
+        self.last_song_id += 1;
+        self.songs.insert(self.last_song_id, song_data.clone());
+        Ok(self.last_song_id)
+    }
+
+    fn insert_fingerprints(&mut self, song_id: i32, fingerprints: &[Fingerprint]) -> Result<()> {
+        if !self.songs.contains_key(&song_id) {
+            return Err(Error::SongNotFound(song_id));
+        }
+        let song_fingerprints = self.fingerprints.entry(song_id).or_default();
+        for fingerprint in fingerprints {
+            if song_fingerprints.insert(fingerprint.clone()) {
+                self.postings
+                    .entry(fingerprint.address)
+                    .or_default()
+                    .push(Posting {
+                        anchor_address: fingerprint.anchor_address,
+                        anchor_time: fingerprint.anchor_time,
+                        song_id,
+                    });
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each_match(
+        &self,
+        addresses: &[u32],
+        visit: &mut dyn FnMut(FingerprintData) -> Result<()>,
+    ) -> Result<()> {
+        let mut rows = Vec::new();
+        for address in unique_sorted(addresses.iter().copied()) {
+            let Some(postings) = self.postings.get(&address) else {
+                continue;
+            };
+            rows.extend(postings.iter().map(|posting| FingerprintData {
+                fingerprint: Fingerprint {
+                    address,
+                    anchor_address: posting.anchor_address,
+                    anchor_time: posting.anchor_time,
+                },
+                song_id: posting.song_id,
+            }));
+            rows.sort_unstable_by_key(posting_order);
+            for row in rows.drain(..) {
+                visit(row)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_song(&self, song_id: i32) -> Result<SongData> {
+        self.songs
+            .get(&song_id)
+            .cloned()
+            .ok_or(Error::SongNotFound(song_id))
+    }
+
+    fn remove_song(&mut self, song_id: i32) -> Result<()> {
+        if self.songs.remove(&song_id).is_none() {
+            return Err(Error::SongNotFound(song_id));
+        }
+        let fingerprints = self.fingerprints.remove(&song_id).unwrap_or_default();
+        let addresses = fingerprints
+            .iter()
+            .map(|fp| fp.address)
+            .collect::<HashSet<_>>();
+        for address in addresses {
+            if let Some(postings) = self.postings.get_mut(&address) {
+                postings.retain(|posting| posting.song_id != song_id);
+                if postings.is_empty() {
+                    self.postings.remove(&address);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Songs without fingerprints are left out, as in the SQLite store
+    fn stats(&self) -> Result<Vec<SongStats>> {
+        Ok(self
+            .songs
+            .iter()
+            .filter_map(|(&song_id, song)| {
+                let fingerprints = self.fingerprints.get(&song_id)?;
+                let first = fingerprints.iter().map(|fp| fp.anchor_time).min()?;
+                let last = fingerprints.iter().map(|fp| fp.anchor_time).max()?;
+                Some(SongStats {
+                    song_id,
+                    title: song.title.clone(),
+                    hashes: fingerprints.len(),
+                    duration_ms: last - first,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{db::DbClient, ranking};
+
+    fn fingerprint(address: u32, anchor_time: u32) -> Fingerprint {
+        Fingerprint {
+            address,
+            anchor_address: address / 5,
+            anchor_time,
+        }
+    }
+
+    // A song made of complete neighborhoods: 5 addresses sharing one anchor per step
+    fn song(seed: u32, steps: std::ops::Range<u32>) -> Vec<Fingerprint> {
+        steps
+            .flat_map(|step| {
+                (0..5).map(move |i| fingerprint((step * 7 + seed * 1000) * 5 + i, step * 46))
+            })
+            .collect()
+    }
+
+    fn fill<S: FingerprintStore>(store: &mut S) {
+        for seed in 0..3 {
+            let song_id = store
+                .insert_song(&SongData {
+                    title: format!("song{}", seed),
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut fingerprints = song(seed, 0..100);
+            // Duplicates are ignored by both stores
+            fingerprints.extend(song(seed, 0..10));
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_same_results_as_sqlite() {
+        let mut memory = MemoryStore::new();
+        let mut sqlite = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        fill(&mut memory);
+        fill(&mut sqlite);
+
+        let summary = |result: Vec<ranking::RankingData>| {
+            result
+                .into_iter()
+                .map(|r| (r.data.title, r.score))
+                .collect::<Vec<_>>()
+        };
+        let query = song(1, 20..40);
+        let options = ranking::RankingOptions {
+            rank: 10,
+            scorer: &ranking::LisScorer,
+            min_confidence: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            summary(ranking::search(&memory, query.clone(), &options).unwrap()),
+            summary(ranking::search(&sqlite, query, &options).unwrap())
+        );
+
+        let stats = |stats: Vec<SongStats>| {
+            stats
+                .into_iter()
+                .map(|s| (s.song_id, s.hashes, s.duration_ms))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            stats(memory.stats().unwrap()),
+            stats(sqlite.stats().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_remove_song() {
+        let mut store = MemoryStore::new();
+        fill(&mut store);
+        store.remove_song(2).unwrap();
+        assert!(store.remove_song(2).is_err());
+        assert!(store.get_song(2).is_err());
+
+        let addresses = song(1, 0..100)
+            .iter()
+            .map(|fp| fp.address)
+            .collect::<Vec<_>>();
+        assert!(store.lookup_addresses(&addresses).unwrap().is_empty());
+        assert_eq!(store.stats().unwrap().len(), 2);
+    }
+}
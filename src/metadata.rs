@@ -0,0 +1,80 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use id3::TagLike;
+
+use crate::error::{IoContext, Result};
+
+// Tags found in the file, every field is optional since most files only carry a few of them
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+    pub isrc: Option<String>,
+    pub duration_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub size: u64,
+    // Hex encoded BLAKE3 hash of the whole file
+    pub content_hash: String,
+}
+
+pub fn read_file_info(path: &Path) -> Result<FileInfo> {
+    let mut hasher = blake3::Hasher::new();
+    let mut reader = BufReader::new(File::open(path).with_path(path)?);
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf).with_path(path)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(FileInfo {
+        size: fs::metadata(path).with_path(path)?.len(),
+        content_hash: hasher.finalize().to_hex().to_string(),
+    })
+}
+
+/*
+    Reads the ID3v2 tags of an mp3, the only format that is decoded. A missing or malformed tag is
+    not an error, the song is still indexed with whatever we could find.
+*/
+pub fn read_tags(path: &Path) -> Tags {
+    let Ok(tag) = id3::Tag::read_from_path(path) else {
+        return Tags::default();
+    };
+    Tags {
+        title: tag.title().map(str::to_string),
+        artist: tag.artist().map(str::to_string),
+        album: tag.album().map(str::to_string),
+        track_number: tag.track(),
+        isrc: tag
+            .get("TSRC")
+            .and_then(|frame| frame.content().text())
+            .map(str::to_string),
+        duration_ms: tag.duration(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_untagged_file() {
+        let path = std::env::temp_dir().join(format!("shezem-untagged-{}.mp3", std::process::id()));
+        fs::write(&path, [0xff, 0xfb, 0x90, 0x00]).unwrap();
+        let tags = read_tags(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(tags, Tags::default());
+        assert_eq!(read_tags(Path::new("missing.mp3")), Tags::default());
+    }
+}
@@ -0,0 +1,357 @@
+use rusqlite::{Connection, Transaction};
+
+use crate::error::{Error, Result};
+
+/*
+    The schema version of a database is kept in `PRAGMA user_version`. Opening a database applies every
+    migration above its version in order, inside a single transaction, so a failed upgrade leaves the
+    file untouched.
+
+    Databases written before migrations existed have version 0 but may already contain some of the
+    later tables and columns, so every migration must be safe to run against a partially upgraded schema.
+*/
+
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "songs and fingerprints tables",
+        up: create_base_tables,
+    },
+    Migration {
+        version: 2,
+        description: "settings and Philips sub-fingerprints tables",
+        up: create_settings_and_sub_fingerprints,
+    },
+    Migration {
+        version: 3,
+        description: "song metadata columns",
+        up: add_song_metadata,
+    },
+    Migration {
+        version: 4,
+        description: "song modification time and path index",
+        up: add_song_modified_at,
+    },
+];
+
+pub fn schema_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub fn current_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let version = current_version(conn)?;
+    if version > schema_version() {
+        return Err(Error::IncompatibleIndex(format!(
+            "database schema version {} is newer than the supported version {}, please upgrade shezem-rs",
+            version,
+            schema_version()
+        )));
+    }
+    if version == schema_version() {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        (migration.up)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn add_column_if_missing(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists = tx
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?",
+            table
+        ))?
+        .exists([column])?;
+    if !exists {
+        tx.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn create_base_tables(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS songs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS fingerprints (
+            address INTEGER NOT NULL,
+            anchorAddress INTEGER NOT NULL,
+            anchorTime INTEGER NOT NULL,
+            songID INTEGER NOT NULL,
+            PRIMARY KEY (address, anchorAddress, anchorTime, songID)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn create_settings_and_sub_fingerprints(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS sub_fingerprints (
+            hash INTEGER NOT NULL,
+            frame INTEGER NOT NULL,
+            songID INTEGER NOT NULL,
+            PRIMARY KEY (songID, frame)
+        )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS sub_fingerprints_hash ON sub_fingerprints (hash)",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn add_song_metadata(tx: &Transaction) -> rusqlite::Result<()> {
+    for (column, definition) in [
+        ("artist", "TEXT"),
+        ("album", "TEXT"),
+        ("trackNumber", "INTEGER"),
+        ("isrc", "TEXT"),
+        ("durationMs", "INTEGER"),
+        ("path", "TEXT"),
+        ("fileSize", "INTEGER"),
+        ("contentHash", "TEXT"),
+    ] {
+        add_column_if_missing(tx, "songs", column, definition)?;
+    }
+    Ok(())
+}
+
+fn add_song_modified_at(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "songs", "modifiedAt", "INTEGER")?;
+    tx.execute("CREATE INDEX IF NOT EXISTS songs_path ON songs (path)", [])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+        The schema each released version created in a new database, written out by hand rather than
+        by running `MIGRATIONS`, so a later edit to a migration can't change what the tests upgrade from.
+        Never edit an entry, add one for every new version instead.
+    */
+    const SCHEMAS: &[&str] = &[
+        "",
+        "CREATE TABLE songs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL
+        );
+        CREATE TABLE fingerprints (
+            address INTEGER NOT NULL,
+            anchorAddress INTEGER NOT NULL,
+            anchorTime INTEGER NOT NULL,
+            songID INTEGER NOT NULL,
+            PRIMARY KEY (address, anchorAddress, anchorTime, songID)
+        );",
+        "CREATE TABLE songs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL
+        );
+        CREATE TABLE fingerprints (
+            address INTEGER NOT NULL,
+            anchorAddress INTEGER NOT NULL,
+            anchorTime INTEGER NOT NULL,
+            songID INTEGER NOT NULL,
+            PRIMARY KEY (address, anchorAddress, anchorTime, songID)
+        );
+        CREATE TABLE sub_fingerprints (
+            hash INTEGER NOT NULL,
+            frame INTEGER NOT NULL,
+            songID INTEGER NOT NULL,
+            PRIMARY KEY (songID, frame)
+        );
+        CREATE INDEX sub_fingerprints_hash ON sub_fingerprints (hash);
+        CREATE TABLE settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );",
+        "CREATE TABLE songs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL,
+            artist TEXT,
+            album TEXT,
+            trackNumber INTEGER,
+            isrc TEXT,
+            durationMs INTEGER,
+            path TEXT,
+            fileSize INTEGER,
+            contentHash TEXT
+        );
+        CREATE TABLE fingerprints (
+            address INTEGER NOT NULL,
+            anchorAddress INTEGER NOT NULL,
+            anchorTime INTEGER NOT NULL,
+            songID INTEGER NOT NULL,
+            PRIMARY KEY (address, anchorAddress, anchorTime, songID)
+        );
+        CREATE TABLE sub_fingerprints (
+            hash INTEGER NOT NULL,
+            frame INTEGER NOT NULL,
+            songID INTEGER NOT NULL,
+            PRIMARY KEY (songID, frame)
+        );
+        CREATE INDEX sub_fingerprints_hash ON sub_fingerprints (hash);
+        CREATE TABLE settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );",
+        "CREATE TABLE songs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL,
+            artist TEXT,
+            album TEXT,
+            trackNumber INTEGER,
+            isrc TEXT,
+            durationMs INTEGER,
+            path TEXT,
+            fileSize INTEGER,
+            contentHash TEXT,
+            modifiedAt INTEGER
+        );
+        CREATE INDEX songs_path ON songs (path);
+        CREATE TABLE fingerprints (
+            address INTEGER NOT NULL,
+            anchorAddress INTEGER NOT NULL,
+            anchorTime INTEGER NOT NULL,
+            songID INTEGER NOT NULL,
+            PRIMARY KEY (address, anchorAddress, anchorTime, songID)
+        );
+        CREATE TABLE sub_fingerprints (
+            hash INTEGER NOT NULL,
+            frame INTEGER NOT NULL,
+            songID INTEGER NOT NULL,
+            PRIMARY KEY (songID, frame)
+        );
+        CREATE INDEX sub_fingerprints_hash ON sub_fingerprints (hash);
+        CREATE TABLE settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );",
+    ];
+
+    // Builds a database as the binary shipping `version` would have left it
+    fn fixture(version: u32) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMAS[version as usize]).unwrap();
+        conn.pragma_update(None, "user_version", version).unwrap();
+
+        if version >= 1 {
+            conn.execute("INSERT INTO songs (title) VALUES ('song')", [])
+                .unwrap();
+            conn.execute(
+                "INSERT INTO fingerprints (address, anchorAddress, anchorTime, songID) VALUES (1, 2, 3, 1)",
+                [],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        conn.prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_versions_are_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as u32 + 1, "{}", migration.description);
+        }
+        assert_eq!(SCHEMAS.len() as u32, schema_version() + 1);
+    }
+
+    #[test]
+    fn test_upgrade_from_every_version() {
+        let latest = fixture(schema_version());
+        for version in 0..=schema_version() {
+            let mut conn = fixture(version);
+            migrate(&mut conn).unwrap();
+
+            assert_eq!(current_version(&conn).unwrap(), schema_version());
+            for table in ["songs", "fingerprints", "sub_fingerprints", "settings"] {
+                assert_eq!(columns(&conn, table), columns(&latest, table), "{}", table);
+            }
+            if version >= 1 {
+                let title: String = conn
+                    .query_row("SELECT title FROM songs WHERE id = 1", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(title, "song");
+                let count: i64 = conn
+                    .query_row("SELECT COUNT(*) FROM fingerprints", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(count, 1);
+            }
+        }
+    }
+
+    // Databases made before versioning report version 0 whatever tables they already have
+    #[test]
+    fn test_upgrade_unversioned_databases() {
+        for version in 1..=schema_version() {
+            let mut conn = fixture(version);
+            conn.pragma_update(None, "user_version", 0).unwrap();
+            migrate(&mut conn).unwrap();
+
+            assert_eq!(current_version(&conn).unwrap(), schema_version());
+            assert!(columns(&conn, "songs").contains(&"modifiedAt".to_string()));
+            let count: i64 = conn
+                .query_row("SELECT COUNT(*) FROM songs", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, 1);
+        }
+    }
+
+    #[test]
+    fn test_refuses_newer_database() {
+        let mut conn = fixture(schema_version());
+        conn.pragma_update(None, "user_version", schema_version() + 1)
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+}
@@ -0,0 +1,727 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use memmap2::Mmap;
+
+use crate::{
+    db::{DbClient, SongData, SongStats},
+    error::{Error, IoContext, Result},
+    fingerprint::{Algorithm, Fingerprint, FingerprintData, FingerprintMode},
+    format::{read_varint, write_varint},
+    store::{FingerprintStore, posting_order, unique_sorted},
+};
+
+/*
+    Immutable inverted index of landmark fingerprints, built from a SQLite index and read through
+    mmap. All integers are little-endian, the file is made of a fixed size header and four sections:
+
+        header (112 bytes)
+            magic           4 bytes  "SHZI"
+            version         u16
+            algorithm       u8       always 0 = landmark
+            mode            u8       0 = landmark, 1 = invariant
+            density         u32      peaks per second the index was built with, 0 = every peak
+            block_size      u32      addresses per sparse index block
+            address_count   u64
+            posting_count   u64
+            block_count     u64
+            sections        4 x (offset u64, length u64): postings, directory, blocks, songs
+            body_checksum   u32      CRC-32 of everything after the header
+            header_checksum u32      CRC-32 of the 108 bytes above
+
+        postings    for every address, its postings sorted by (song_id, anchor_time, anchor_address):
+                    song_id delta, anchor_time (delta from the previous posting of the same song,
+                    absolute for a new song) and anchor_address, all varints
+        directory   for every address in ascending order: address delta from the previous address of
+                    the block, posting count and byte length of its postings, all varints
+        blocks      one fixed size entry per block_size addresses: first address u32, offset of the
+                    block in the directory u64 and offset of its first postings u64
+        songs       JSON array of (song_id, song)
+
+    A lookup binary searches the blocks, then decodes at most block_size directory entries.
+*/
+
+const MAGIC: &[u8; 4] = b"SHZI";
+pub const INDEX_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 112;
+const BLOCK_SIZE: usize = 64;
+const BLOCK_ENTRY_SIZE: usize = 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Section {
+    offset: u64,
+    len: u64,
+}
+
+impl Section {
+    fn range(&self) -> std::ops::Range<usize> {
+        self.offset as usize..(self.offset + self.len) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexHeader {
+    pub version: u16,
+    pub mode: FingerprintMode,
+    pub density: Option<usize>,
+    pub address_count: u64,
+    pub posting_count: u64,
+    block_size: u32,
+    block_count: u64,
+    postings: Section,
+    directory: Section,
+    blocks: Section,
+    songs: Section,
+    body_checksum: u32,
+}
+
+impl IndexHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.push(0);
+        bytes.push(match self.mode {
+            FingerprintMode::Landmark => 0,
+            FingerprintMode::Invariant => 1,
+        });
+        bytes.extend_from_slice(&(self.density.unwrap_or(0) as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.block_size.to_le_bytes());
+        for value in [self.address_count, self.posting_count, self.block_count] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for section in [self.postings, self.directory, self.blocks, self.songs] {
+            bytes.extend_from_slice(&section.offset.to_le_bytes());
+            bytes.extend_from_slice(&section.len.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.body_checksum.to_le_bytes());
+        let checksum = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+            return Err(corrupt("not a shezem-rs index file"));
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version > INDEX_VERSION {
+            return Err(Error::IncompatibleIndex(format!(
+                "index file version {} is newer than the supported version {}",
+                version, INDEX_VERSION
+            )));
+        }
+        if crc32fast::hash(&bytes[..HEADER_SIZE - 4]) != u32_at(bytes, HEADER_SIZE - 4) {
+            return Err(corrupt("index file header is corrupted"));
+        }
+        if bytes[6] != 0 {
+            return Err(Error::IncompatibleIndex(format!(
+                "unknown algorithm {}",
+                bytes[6]
+            )));
+        }
+        let mode = match bytes[7] {
+            0 => FingerprintMode::Landmark,
+            1 => FingerprintMode::Invariant,
+            mode => {
+                return Err(Error::IncompatibleIndex(format!(
+                    "unknown fingerprint mode {}",
+                    mode
+                )));
+            }
+        };
+        let section = |i: usize| Section {
+            offset: u64_at(bytes, 40 + i * 16),
+            len: u64_at(bytes, 48 + i * 16),
+        };
+
+        let header = IndexHeader {
+            version,
+            mode,
+            density: match u32_at(bytes, 8) {
+                0 => None,
+                density => Some(density as usize),
+            },
+            block_size: u32_at(bytes, 12),
+            address_count: u64_at(bytes, 16),
+            posting_count: u64_at(bytes, 24),
+            block_count: u64_at(bytes, 32),
+            postings: section(0),
+            directory: section(1),
+            blocks: section(2),
+            songs: section(3),
+            body_checksum: u32_at(bytes, 104),
+        };
+        for section in [
+            header.postings,
+            header.directory,
+            header.blocks,
+            header.songs,
+        ] {
+            if section.offset < HEADER_SIZE as u64
+                || section.offset.saturating_add(section.len) > bytes.len() as u64
+            {
+                return Err(corrupt("index file is truncated"));
+            }
+        }
+        if header.blocks.len != header.block_count * BLOCK_ENTRY_SIZE as u64 {
+            return Err(corrupt("index file block table is corrupted"));
+        }
+        Ok(header)
+    }
+}
+
+fn corrupt(reason: &str) -> Error {
+    Error::CorruptIndex(reason.to_string())
+}
+
+fn read_only() -> Error {
+    Error::Unsupported("index files are read-only, rebuild them with build-index".to_string())
+}
+
+// Varints of the directory and the postings, a malformed one can only come from a damaged file
+fn next_varint(bytes: &mut &[u8]) -> Result<u64> {
+    read_varint(bytes).map_err(|_| corrupt("index file is corrupted"))
+}
+
+fn next_u32(bytes: &mut &[u8]) -> Result<u32> {
+    u32::try_from(next_varint(bytes)?).map_err(|_| corrupt("index file is corrupted"))
+}
+
+fn checked<T>(value: Option<T>) -> Result<T> {
+    value.ok_or_else(|| corrupt("index file is corrupted"))
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap())
+}
+
+// Keeps track of the position and checksum of everything written after the header
+struct BodyWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+    position: u64,
+}
+
+impl<W: Write> Write for BodyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Default)]
+struct DirectoryBuilder {
+    directory: Vec<u8>,
+    blocks: Vec<u8>,
+    address_count: u64,
+    previous_address: u32,
+}
+
+impl DirectoryBuilder {
+    fn push(&mut self, address: u32, count: usize, postings_offset: u64, len: u64) -> Result<()> {
+        if self.address_count.is_multiple_of(BLOCK_SIZE as u64) {
+            self.blocks.extend_from_slice(&address.to_le_bytes());
+            self.blocks
+                .extend_from_slice(&(self.directory.len() as u64).to_le_bytes());
+            self.blocks
+                .extend_from_slice(&postings_offset.to_le_bytes());
+            self.previous_address = address;
+        }
+        write_varint(
+            &mut self.directory,
+            (address - self.previous_address) as u64,
+        )?;
+        write_varint(&mut self.directory, count as u64)?;
+        write_varint(&mut self.directory, len)?;
+        self.previous_address = address;
+        self.address_count += 1;
+        Ok(())
+    }
+}
+
+// Postings of one address as (song_id, anchor_time, anchor_address)
+fn write_postings<W: Write>(writer: &mut W, postings: &mut [(i32, u32, u32)]) -> Result<()> {
+    postings.sort_unstable();
+    let (mut previous_song, mut previous_time) = (0, 0);
+    for &(song_id, anchor_time, anchor_address) in postings.iter() {
+        if song_id != previous_song {
+            previous_time = 0;
+        }
+        write_varint(writer, (song_id - previous_song) as u64)?;
+        write_varint(writer, (anchor_time - previous_time) as u64)?;
+        write_varint(writer, anchor_address as u64)?;
+        (previous_song, previous_time) = (song_id, anchor_time);
+    }
+    Ok(())
+}
+
+pub struct MmapIndex {
+    mmap: Mmap,
+    header: IndexHeader,
+    songs: BTreeMap<i32, SongData>,
+}
+
+impl MmapIndex {
+    /*
+        Checks the header and the block table, the rest of the file is only paged in by the lookups
+        that need it. `verify` checks the whole file.
+    */
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_path(path)?;
+        // SAFETY: index files are written once by `build` and replaced by renaming, never modified in place
+        let mmap = unsafe { Mmap::map(&file).with_path(path)? };
+        let header = IndexHeader::decode(&mmap)?;
+        let index = MmapIndex {
+            songs: BTreeMap::new(),
+            mmap,
+            header,
+        };
+        index.check_blocks()?;
+        let songs =
+            serde_json::from_slice::<Vec<(i32, SongData)>>(&index.mmap[index.header.songs.range()])
+                .map_err(|_| corrupt("index file song table is corrupted"))?
+                .into_iter()
+                .collect();
+        Ok(MmapIndex { songs, ..index })
+    }
+
+    /*
+        Converts the landmark fingerprints of a SQLite index. Postings are streamed to the file in
+        address order, only the directory and the block table are kept in memory until the end.
+        The index is written next to `path` and renamed over it, so readers of the previous one
+        keep their mapping.
+    */
+    pub fn build(db_client: &DbClient, path: &Path) -> Result<Self> {
+        if db_client.algorithm()? != Algorithm::Landmark {
+            return Err(Error::Unsupported(
+                "only landmark indexes can be converted to an index file".to_string(),
+            ));
+        }
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+        let index = Self::write(db_client, &temp_path)
+            .with_path(&temp_path)
+            .and_then(|()| Self::open(&temp_path))
+            .and_then(|index| index.verify().map(|()| index))
+            .and_then(|index| fs::rename(&temp_path, path).with_path(path).map(|()| index));
+        if index.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        index
+    }
+
+    fn write(db_client: &DbClient, path: &Path) -> Result<()> {
+        let songs = db_client.songs()?;
+
+        let mut writer = BodyWriter {
+            inner: BufWriter::new(File::create(path)?),
+            hasher: crc32fast::Hasher::new(),
+            position: HEADER_SIZE as u64,
+        };
+        writer.inner.write_all(&[0; HEADER_SIZE])?;
+
+        let mut directory = DirectoryBuilder::default();
+        let mut posting_count = 0;
+        let mut current: Option<u32> = None;
+        let mut postings = Vec::new();
+        let mut flush = |writer: &mut BodyWriter<_>,
+                         address: u32,
+                         postings: &mut Vec<(i32, u32, u32)>|
+         -> Result<()> {
+            let start = writer.position;
+            write_postings(writer, postings)?;
+            directory.push(
+                address,
+                postings.len(),
+                start - HEADER_SIZE as u64,
+                writer.position - start,
+            )?;
+            posting_count += postings.len() as u64;
+            postings.clear();
+            Ok(())
+        };
+
+        db_client.for_each_fingerprint(|row| {
+            let address = row.fingerprint.address;
+            if let Some(previous) = current
+                && previous != address
+            {
+                flush(&mut writer, previous, &mut postings)?;
+            }
+            current = Some(address);
+            postings.push((
+                row.song_id,
+                row.fingerprint.anchor_time,
+                row.fingerprint.anchor_address,
+            ));
+            Ok(())
+        })?;
+        if let Some(address) = current {
+            flush(&mut writer, address, &mut postings)?;
+        }
+
+        let postings_section = Section {
+            offset: HEADER_SIZE as u64,
+            len: writer.position - HEADER_SIZE as u64,
+        };
+        let section = |writer: &mut BodyWriter<_>, bytes: &[u8]| -> Result<Section> {
+            let offset = writer.position;
+            writer.write_all(bytes)?;
+            Ok(Section {
+                offset,
+                len: bytes.len() as u64,
+            })
+        };
+        let directory_section = section(&mut writer, &directory.directory)?;
+        let blocks_section = section(&mut writer, &directory.blocks)?;
+        let songs = serde_json::to_vec(&songs).map_err(std::io::Error::from)?;
+        let songs_section = section(&mut writer, &songs)?;
+
+        let header = IndexHeader {
+            version: INDEX_VERSION,
+            mode: db_client.fingerprint_mode()?,
+            density: db_client.density()?,
+            address_count: directory.address_count,
+            posting_count,
+            block_size: BLOCK_SIZE as u32,
+            block_count: (directory.blocks.len() / BLOCK_ENTRY_SIZE) as u64,
+            postings: postings_section,
+            directory: directory_section,
+            blocks: blocks_section,
+            songs: songs_section,
+            body_checksum: writer.hasher.finalize(),
+        };
+        let mut file = writer.inner.into_inner().map_err(|err| err.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header.encode())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn header(&self) -> &IndexHeader {
+        &self.header
+    }
+
+    pub fn song_count(&self) -> usize {
+        self.songs.len()
+    }
+
+    pub fn file_size(&self) -> usize {
+        self.mmap.len()
+    }
+
+    // Checks the body checksum, which reads the whole file
+    pub fn verify(&self) -> Result<()> {
+        if crc32fast::hash(&self.mmap[HEADER_SIZE..]) != self.header.body_checksum {
+            return Err(corrupt("index file is corrupted"));
+        }
+        Ok(())
+    }
+
+    // Lookups binary search the blocks, they must be in order and point inside their sections
+    fn check_blocks(&self) -> Result<()> {
+        let mut previous: Option<(u32, usize, usize)> = None;
+        for index in 0..self.header.block_count as usize {
+            let block = self.block(index);
+            let (address, directory, postings) = block;
+            let inside = directory <= self.header.directory.len as usize
+                && postings <= self.header.postings.len as usize;
+            let ordered = previous.is_none_or(
+                |(previous_address, previous_directory, previous_postings)| {
+                    previous_address < address
+                        && previous_directory <= directory
+                        && previous_postings <= postings
+                },
+            );
+            if !inside || !ordered {
+                return Err(corrupt("index file block table is corrupted"));
+            }
+            previous = Some(block);
+        }
+        Ok(())
+    }
+
+    fn block(&self, index: usize) -> (u32, usize, usize) {
+        let blocks = &self.mmap[self.header.blocks.range()];
+        let pos = index * BLOCK_ENTRY_SIZE;
+        (
+            u32_at(blocks, pos),
+            u64_at(blocks, pos + 4) as usize,
+            u64_at(blocks, pos + 12) as usize,
+        )
+    }
+
+    // Calls `f` with every (address, postings) of a block, stops early when it returns false
+    fn scan_block(
+        &self,
+        index: usize,
+        mut f: impl FnMut(u32, usize, &[u8]) -> Result<bool>,
+    ) -> Result<()> {
+        let (mut address, directory_start, mut postings_offset) = self.block(index);
+        let directory_end = if index + 1 < self.header.block_count as usize {
+            self.block(index + 1).1
+        } else {
+            self.header.directory.len as usize
+        };
+        let postings = &self.mmap[self.header.postings.range()];
+        let mut directory = self.mmap[self.header.directory.range()]
+            .get(directory_start..directory_end)
+            .ok_or_else(|| corrupt("index file directory is corrupted"))?;
+
+        while !directory.is_empty() {
+            address = checked(address.checked_add(next_u32(&mut directory)?))?;
+            let count = next_varint(&mut directory)? as usize;
+            let len = next_varint(&mut directory)? as usize;
+            let end = checked(postings_offset.checked_add(len))?;
+            let Some(bytes) = postings.get(postings_offset..end) else {
+                return Err(corrupt("index file postings are corrupted"));
+            };
+            if !f(address, count, bytes)? {
+                break;
+            }
+            postings_offset = end;
+        }
+        Ok(())
+    }
+
+    fn decode_postings(
+        address: u32,
+        count: usize,
+        mut bytes: &[u8],
+        out: &mut Vec<FingerprintData>,
+    ) -> Result<()> {
+        let (mut song_id, mut anchor_time) = (0i32, 0u32);
+        for _ in 0..count {
+            let song_delta = next_u32(&mut bytes)?;
+            if song_delta != 0 {
+                anchor_time = 0;
+            }
+            song_id = checked(song_id.checked_add_unsigned(song_delta))?;
+            anchor_time = checked(anchor_time.checked_add(next_u32(&mut bytes)?))?;
+            out.push(FingerprintData {
+                fingerprint: Fingerprint {
+                    address,
+                    anchor_address: next_u32(&mut bytes)?,
+                    anchor_time,
+                },
+                song_id,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl FingerprintStore for MmapIndex {
+    fn insert_song(&mut self, _song_data: &SongData) -> Result<i32> {
+        Err(read_only())
+    }
+
+    fn insert_fingerprints(&mut self, _song_id: i32, _fingerprints: &[Fingerprint]) -> Result<()> {
+        Err(read_only())
+    }
+
+    fn for_each_match(
+        &self,
+        addresses: &[u32],
+        visit: &mut dyn FnMut(FingerprintData) -> Result<()>,
+    ) -> Result<()> {
+        let block_count = self.header.block_count as usize;
+        let mut rows = Vec::new();
+        for address in unique_sorted(addresses.iter().copied()) {
+            // Last block starting at or before the address
+            let (mut low, mut high) = (0, block_count);
+            while low < high {
+                let mid = (low + high) / 2;
+                if self.block(mid).0 <= address {
+                    low = mid + 1;
+                } else {
+                    high = mid;
+                }
+            }
+            if low == 0 {
+                continue;
+            }
+            self.scan_block(low - 1, |found, count, bytes| {
+                if found == address {
+                    Self::decode_postings(found, count, bytes, &mut rows)?;
+                }
+                Ok(found < address)
+            })?;
+            // Postings are stored by song, the store contract wants them in primary key order
+            rows.sort_unstable_by_key(posting_order);
+            for row in rows.drain(..) {
+                visit(row)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_song(&self, song_id: i32) -> Result<SongData> {
+        self.songs
+            .get(&song_id)
+            .cloned()
+            .ok_or(Error::SongNotFound(song_id))
+    }
+
+    fn remove_song(&mut self, _song_id: i32) -> Result<()> {
+        Err(read_only())
+    }
+
+    // Decodes every posting, the index doesn't keep per song totals
+    fn stats(&self) -> Result<Vec<SongStats>> {
+        let mut totals: HashMap<i32, (usize, u32, u32)> = HashMap::new();
+        let mut rows = Vec::new();
+        for block in 0..self.header.block_count as usize {
+            self.scan_block(block, |address, count, bytes| {
+                Self::decode_postings(address, count, bytes, &mut rows)?;
+                for row in rows.drain(..) {
+                    let time = row.fingerprint.anchor_time;
+                    let total = totals.entry(row.song_id).or_insert((0, time, time));
+                    *total = (total.0 + 1, total.1.min(time), total.2.max(time));
+                }
+                Ok(true)
+            })?;
+        }
+
+        Ok(self
+            .songs
+            .iter()
+            .filter_map(|(&song_id, song)| {
+                let &(hashes, first, last) = totals.get(&song_id)?;
+                Some(SongStats {
+                    song_id,
+                    title: song.title.clone(),
+                    hashes,
+                    duration_ms: last - first,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn sqlite_index() -> DbClient {
+        let mut db_client = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for seed in 0..3u32 {
+            let song_id = db_client
+                .insert_song(&SongData {
+                    title: format!("song{}", seed),
+                    ..Default::default()
+                })
+                .unwrap();
+            // Enough addresses for several blocks, some of them shared between songs
+            let fingerprints = (0..500u32)
+                .map(|i| Fingerprint {
+                    address: i * 3 + seed * 699,
+                    anchor_address: i ^ seed,
+                    anchor_time: i * 46 + seed,
+                })
+                .collect::<Vec<_>>();
+            db_client
+                .insert_fingerprints(song_id, &fingerprints)
+                .unwrap();
+        }
+        db_client
+    }
+
+    fn sorted(rows: Vec<FingerprintData>) -> Vec<(u32, u32, u32, i32)> {
+        let mut rows = rows
+            .into_iter()
+            .map(|row| {
+                let fp = row.fingerprint;
+                (fp.address, fp.anchor_address, fp.anchor_time, row.song_id)
+            })
+            .collect::<Vec<_>>();
+        rows.sort_unstable();
+        rows
+    }
+
+    #[test]
+    fn test_same_lookups_as_sqlite() {
+        let db_client = sqlite_index();
+        let path = std::env::temp_dir().join(format!("shezem-test-{}.shzi", std::process::id()));
+        let index = MmapIndex::build(&db_client, &path).unwrap();
+
+        assert_eq!(index.header().address_count, 966);
+        let addresses = (0..3000).step_by(7).collect::<Vec<_>>();
+        assert_eq!(
+            sorted(index.lookup_addresses(&addresses).unwrap()),
+            sorted(db_client.lookup_addresses(&addresses).unwrap())
+        );
+        assert_eq!(index.get_song(2).unwrap().title, "song1");
+
+        let stats = |stats: Vec<SongStats>| {
+            stats
+                .into_iter()
+                .map(|s| (s.song_id, s.hashes, s.duration_ms))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            stats(index.stats().unwrap()),
+            stats(db_client.stats().unwrap())
+        );
+
+        // A flipped byte of the header or the block table is caught when opening, one of the body by `verify`
+        let blocks = index.header().blocks.range();
+        drop(index);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[20] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            MmapIndex::open(&path),
+            Err(Error::CorruptIndex(_))
+        ));
+        bytes[20] ^= 1;
+        let first_address = bytes[blocks.start..blocks.start + 4].to_vec();
+        bytes[blocks.start..blocks.start + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            MmapIndex::open(&path),
+            Err(Error::CorruptIndex(_))
+        ));
+        bytes[blocks.start..blocks.start + 4].copy_from_slice(&first_address);
+        bytes[HEADER_SIZE] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            MmapIndex::open(&path).unwrap().verify(),
+            Err(Error::CorruptIndex(_))
+        ));
+
+        // Rebuilding replaces the file, the mapping of the damaged one stays readable
+        let damaged = std::fs::File::open(&path).unwrap();
+        let index = MmapIndex::build(&db_client, &path).unwrap();
+        assert_eq!(index.song_count(), 3);
+        assert_eq!(damaged.metadata().unwrap().len(), bytes.len() as u64);
+        std::fs::remove_file(&path).unwrap();
+
+        // Checksums can match a file that was written wrong, its values still can't overflow
+        let mut bytes = Vec::new();
+        for value in [i32::MAX as u64, 0, 0, 1, 0, 0] {
+            write_varint(&mut bytes, value).unwrap();
+        }
+        assert!(matches!(
+            MmapIndex::decode_postings(0, 2, &bytes, &mut Vec::new()),
+            Err(Error::CorruptIndex(_))
+        ));
+    }
+}
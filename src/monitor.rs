@@ -0,0 +1,379 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+use crate::{
+    DOWNSAMPLE_FACTOR, IndexOptions,
+    db::SongData,
+    error::{Error, Result},
+    fingerprint::{Algorithm, FingerprintMode, Fingerprinter, LandmarkFingerprinter},
+    ranking::{self, LisScorer, RankingData, RankingOptions},
+    sample::Sample,
+    spectrogram::DensityController,
+    store::FingerprintStore,
+};
+
+/*
+    Identification of a live feed. The last `window_ms` of audio are kept and searched every
+    `interval_ms`. A song has to be the confident match of `start_after` searches in a row before it
+    starts, and it stops once `stop_after` searches in a row no longer find it even with the lower
+    `stop_confidence`, so a noisy passage doesn't end a song and a chance match doesn't start one.
+*/
+
+#[derive(Debug, Clone, Copy)]
+pub struct MonitorOptions {
+    pub window_ms: u32,
+    pub interval_ms: u32,
+    pub start_confidence: f32,
+    pub stop_confidence: f32,
+    pub start_after: u32,
+    pub stop_after: u32,
+}
+
+impl Default for MonitorOptions {
+    fn default() -> Self {
+        MonitorOptions {
+            window_ms: 10000,
+            interval_ms: 2000,
+            start_confidence: 0.5,
+            stop_confidence: 0.2,
+            start_after: 2,
+            stop_after: 3,
+        }
+    }
+}
+
+// Times are in seconds since the start of the stream
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MonitorEvent {
+    Start {
+        time: f32,
+        song: SongData,
+        // Time of the song at `time`
+        song_time: f32,
+        confidence: f32,
+    },
+    Stop {
+        time: f32,
+        song: SongData,
+    },
+}
+
+struct Track {
+    song_id: i32,
+    song: SongData,
+    playing: bool,
+    // Confident searches in a row, before the song starts
+    hits: u32,
+    // Searches in a row without the song
+    misses: u32,
+    first_seen: f32,
+    last_seen: f32,
+    song_time: f32,
+    confidence: f32,
+}
+
+pub struct Monitor<'a, S: FingerprintStore + ?Sized> {
+    store: &'a S,
+    fingerprinter: LandmarkFingerprinter,
+    options: MonitorOptions,
+    sample_rate: usize,
+    // Mono samples of the last window
+    buffer: VecDeque<f32>,
+    // Samples received since the start of the stream
+    received: u64,
+    // Samples received since the last search
+    pending: u64,
+    tracks: Vec<Track>,
+}
+
+impl<'a, S: FingerprintStore + ?Sized> Monitor<'a, S> {
+    // `index` are the options the store was built with, the feed must have the sample rate of the indexed files
+    pub fn new(
+        store: &'a S,
+        index: IndexOptions,
+        sample_rate: usize,
+        options: MonitorOptions,
+    ) -> Result<Self> {
+        if index.algorithm != Algorithm::Landmark {
+            return Err(Error::Unsupported(
+                "only landmark indexes can be monitored".to_string(),
+            ));
+        }
+        Ok(Monitor {
+            store,
+            fingerprinter: LandmarkFingerprinter {
+                mode: index.mode,
+                density: index.density.map(DensityController::new),
+            },
+            options,
+            sample_rate,
+            buffer: VecDeque::new(),
+            received: 0,
+            pending: 0,
+            tracks: Vec::new(),
+        })
+    }
+
+    fn ms_to_samples(&self, ms: u32) -> u64 {
+        ms as u64 * self.sample_rate as u64 / 1000
+    }
+
+    // Takes the next mono samples of the feed, the events are those the samples led to
+    pub fn push(&mut self, samples: &[f32]) -> Result<Vec<MonitorEvent>> {
+        let window = self.ms_to_samples(self.options.window_ms) as usize;
+        let interval = self.ms_to_samples(self.options.interval_ms).max(1);
+
+        let mut events = Vec::new();
+        let mut samples = samples;
+        while !samples.is_empty() {
+            let take = samples.len().min((interval - self.pending) as usize);
+            let (now, rest) = samples.split_at(take);
+            samples = rest;
+
+            self.buffer.extend(now);
+            let excess = self.buffer.len().saturating_sub(window);
+            self.buffer.drain(..excess);
+            self.received += take as u64;
+            self.pending += take as u64;
+            if self.pending == interval {
+                self.pending = 0;
+                events.extend(self.search()?);
+            }
+        }
+        Ok(events)
+    }
+
+    // Ends the stream, every song still playing stops where it was last found
+    pub fn finish(&mut self) -> Vec<MonitorEvent> {
+        self.tracks
+            .drain(..)
+            .filter(|track| track.playing)
+            .map(|track| MonitorEvent::Stop {
+                time: track.last_seen,
+                song: track.song,
+            })
+            .collect()
+    }
+
+    fn search(&mut self) -> Result<Vec<MonitorEvent>> {
+        let mut sample = Sample {
+            sample: self.buffer.iter().copied().collect(),
+            sample_rate: self.sample_rate,
+        };
+        let fingerprints = self
+            .fingerprinter
+            .generate(&sample.downsample(DOWNSAMPLE_FACTOR));
+        let options = RankingOptions {
+            rank: 5,
+            scorer: &LisScorer,
+            min_confidence: self.options.stop_confidence,
+            ..Default::default()
+        };
+        let ranking = match self.fingerprinter.mode {
+            FingerprintMode::Landmark => ranking::search(self.store, fingerprints, &options)?,
+            FingerprintMode::Invariant => {
+                ranking::search_invariant(self.store, fingerprints, &options)?
+            }
+        };
+        let window_start =
+            (self.received - self.buffer.len() as u64) as f32 / self.sample_rate as f32;
+        Ok(self.update(&ranking, window_start))
+    }
+
+    fn update(&mut self, ranking: &[RankingData], window_start: f32) -> Vec<MonitorEvent> {
+        let mut events = Vec::new();
+        let mut seen = vec![false; self.tracks.len()];
+        for result in ranking {
+            let position = &result.position;
+            // Copies of a song indexed twice have the same tags, only their ids tell them apart
+            let index = match self.tracks.iter().position(|t| t.song_id == result.song_id) {
+                Some(index) => index,
+                None => {
+                    self.tracks.push(Track {
+                        song_id: result.song_id,
+                        song: result.data.clone(),
+                        playing: false,
+                        hits: 0,
+                        misses: 0,
+                        first_seen: window_start + position.query_start,
+                        last_seen: 0.0,
+                        song_time: position.reference_start,
+                        confidence: 0.0,
+                    });
+                    seen.push(false);
+                    self.tracks.len() - 1
+                }
+            };
+            seen[index] = true;
+
+            let track = &mut self.tracks[index];
+            track.misses = 0;
+            track.last_seen = window_start + position.query_end;
+            if track.playing {
+                continue;
+            }
+            if result.confidence < self.options.start_confidence {
+                track.hits = 0;
+                continue;
+            }
+            track.hits += 1;
+            track.confidence = track.confidence.max(result.confidence);
+            if track.hits >= self.options.start_after {
+                track.playing = true;
+                events.push(MonitorEvent::Start {
+                    time: track.first_seen,
+                    song: track.song.clone(),
+                    song_time: track.song_time,
+                    confidence: track.confidence,
+                });
+            }
+        }
+
+        // A song that isn't playing yet has to be found again in the very next search
+        let stop_after = self.options.stop_after;
+        let mut index = 0;
+        self.tracks.retain_mut(|track| {
+            let found = seen[index];
+            index += 1;
+            if found {
+                return true;
+            }
+            track.misses += 1;
+            if !track.playing {
+                return false;
+            }
+            if track.misses < stop_after {
+                return true;
+            }
+            events.push(MonitorEvent::Stop {
+                time: track.last_seen,
+                song: track.song.clone(),
+            });
+            false
+        });
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::db::DbClient;
+
+    const SAMPLE_RATE: usize = 44100;
+
+    // Two voices playing a new pair of pseudo random notes every 400ms, different for every seed
+    fn song(seed: u32, seconds: u32) -> Vec<f32> {
+        let note_length = SAMPLE_RATE * 2 / 5;
+        (0..seconds as usize * SAMPLE_RATE)
+            .map(|i| {
+                let note = (i / note_length) as u32;
+                (0..2)
+                    .map(|voice| {
+                        let hash = (note.wrapping_mul(2654435761)
+                            ^ (seed * 2 + voice).wrapping_mul(40503))
+                        .wrapping_mul(2246822519);
+                        let freq = 200.0 + ((hash >> 16) % 97) as f32 * 25.0;
+                        (i as f32 * freq * std::f32::consts::TAU / SAMPLE_RATE as f32).sin()
+                            * 8000.0
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_start_and_stop() {
+        let index = IndexOptions::default();
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for seed in 1..=2 {
+            let song_id = store
+                .insert_song(&SongData {
+                    title: seed.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut sample = Sample {
+                sample: song(seed, 40),
+                sample_rate: SAMPLE_RATE,
+            };
+            let fingerprints = LandmarkFingerprinter {
+                mode: index.mode,
+                density: None,
+            }
+            .generate(&sample.downsample(DOWNSAMPLE_FACTOR));
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+
+        // 6s of silence, 20s of song 2 from 10s on, then silence again
+        let silence = vec![0.0; 6 * SAMPLE_RATE];
+        let feed = [&silence[..], &song(2, 30)[10 * SAMPLE_RATE..], &silence[..]].concat();
+        let mut monitor =
+            Monitor::new(&store, index, SAMPLE_RATE, MonitorOptions::default()).unwrap();
+        let mut events = Vec::new();
+        for chunk in feed.chunks(4096) {
+            events.extend(monitor.push(chunk).unwrap());
+        }
+        events.extend(monitor.finish());
+
+        let [
+            MonitorEvent::Start {
+                time: start,
+                song,
+                song_time,
+                ..
+            },
+            MonitorEvent::Stop { time: stop, .. },
+        ] = &events[..]
+        else {
+            panic!("expected one start and one stop, got {:?}", events);
+        };
+        assert_eq!(song.title, "2");
+        assert!((start - 6.0).abs() < 1.0, "started at {}", start);
+        assert!((song_time - 10.0).abs() < 1.0, "song time {}", song_time);
+        assert!((stop - 26.0).abs() < 1.0, "stopped at {}", stop);
+    }
+
+    #[test]
+    fn test_start_needs_confident_searches_in_a_row() {
+        let store = crate::memory::MemoryStore::new();
+        let mut monitor = Monitor::new(
+            &store,
+            IndexOptions::default(),
+            SAMPLE_RATE,
+            MonitorOptions::default(),
+        )
+        .unwrap();
+        // Two indexed copies of the same file, nothing but the id differs
+        let found = |song_id, confidence| RankingData {
+            song_id,
+            data: SongData {
+                title: "copy".to_string(),
+                ..Default::default()
+            },
+            score: 10,
+            speed: 1.0,
+            position: Default::default(),
+            confidence,
+        };
+
+        assert!(monitor.update(&[found(1, 0.9)], 0.0).is_empty());
+        assert!(monitor.update(&[found(1, 0.3)], 2.0).is_empty());
+        assert!(monitor.update(&[found(1, 0.9)], 4.0).is_empty());
+        assert_eq!(monitor.update(&[found(1, 0.9)], 6.0).len(), 1);
+
+        assert!(
+            monitor
+                .update(&[found(1, 0.9), found(2, 0.9)], 8.0)
+                .is_empty()
+        );
+        assert_eq!(
+            monitor.update(&[found(1, 0.9), found(2, 0.9)], 10.0).len(),
+            1
+        );
+    }
+}
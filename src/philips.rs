@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fingerprint::Fingerprinter,
+    sample::Sample,
+    spectrogram::{WindowSize, generate_spectrogram},
+};
+
+/*
+    Haitsma-Kalker sub-fingerprints, see "A Highly Robust Audio Fingerprinting System" (Philips Research).
+
+    Every frame of ~0.37s is split into 33 logarithmically spaced bands between 300Hz and 2000Hz.
+    Bit m of the frame's 32-bit sub-fingerprint tells whether the energy difference between band m
+    and band m + 1 grew or shrank compared to the previous frame. Only the sign of energy differences
+    is kept, so the bits survive equalisation and compression much better than spectral peaks do.
+*/
+
+pub const WINDOW_SIZE: WindowSize = WindowSize::S4096;
+// Consecutive frames overlap by 31/32 of the window
+pub const HOP_SIZE: usize = 128;
+// Number of consecutive sub-fingerprints compared when matching
+pub const BLOCK_SIZE: usize = 256;
+// A block with a higher bit error rate than this is not a match
+pub const BER_THRESHOLD: f32 = 0.35;
+
+const BANDS: usize = 33;
+const MIN_FREQ: f32 = 300.0;
+const MAX_FREQ: f32 = 2000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubFingerprint {
+    pub hash: u32,
+    pub frame: u32,
+}
+
+#[derive(Debug)]
+pub struct SubFingerprintData {
+    pub sub_fingerprint: SubFingerprint,
+    pub song_id: i32,
+}
+
+pub struct PhilipsFingerprinter;
+
+impl Fingerprinter for PhilipsFingerprinter {
+    type Output = SubFingerprint;
+
+    fn generate(&self, sample: &Sample) -> Vec<SubFingerprint> {
+        let window_size: usize = WINDOW_SIZE.into();
+        if sample.sample.len() < window_size || sample.sample_rate == 0 {
+            return Vec::new();
+        }
+
+        let band_edges = (0..=BANDS)
+            .map(|m| {
+                let freq = MIN_FREQ * (MAX_FREQ / MIN_FREQ).powf(m as f32 / BANDS as f32);
+                ((freq * window_size as f32 / sample.sample_rate as f32) as usize)
+                    .min(window_size / 2)
+            })
+            .collect::<Vec<_>>();
+
+        let spectrogram = generate_spectrogram(&sample.sample, WINDOW_SIZE, window_size - HOP_SIZE);
+        let energies = spectrogram
+            .iter()
+            .map(|window| {
+                band_edges
+                    .windows(2)
+                    .map(|edge| {
+                        window.data[edge[0]..edge[1].max(edge[0] + 1)]
+                            .iter()
+                            .map(|c| c.norm_sqr())
+                            .sum::<f32>()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        energies
+            .windows(2)
+            .enumerate()
+            .map(|(frame, pair)| {
+                let (prev, curr) = (&pair[0], &pair[1]);
+                let mut hash = 0u32;
+                for m in 0..BANDS - 1 {
+                    let diff = (curr[m] - curr[m + 1]) - (prev[m] - prev[m + 1]);
+                    if diff > 0.0 {
+                        hash |= 1 << m;
+                    }
+                }
+                SubFingerprint {
+                    hash,
+                    frame: frame as u32 + 1,
+                }
+            })
+            .collect()
+    }
+}
+
+// Fraction of differing bits between two equally long runs of sub-fingerprints
+pub fn bit_error_rate(a: &[u32], b: &[u32]) -> f32 {
+    let len = a.len().min(b.len());
+    if len == 0 {
+        return 1.0;
+    }
+    let errors = a
+        .iter()
+        .zip(b)
+        .map(|(x, y)| (x ^ y).count_ones())
+        .sum::<u32>();
+    errors as f32 / (len * 32) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_error_rate_identical() {
+        let hashes = vec![0xdeadbeef, 0x12345678, 0];
+        assert_eq!(bit_error_rate(&hashes, &hashes), 0.0);
+    }
+
+    #[test]
+    fn test_bit_error_rate_inverted() {
+        assert_eq!(bit_error_rate(&[0, u32::MAX], &[u32::MAX, 0]), 1.0);
+    }
+
+    #[test]
+    fn test_bit_error_rate_empty() {
+        assert_eq!(bit_error_rate(&[], &[]), 1.0);
+    }
+}
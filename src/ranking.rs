@@ -0,0 +1,1067 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use serde::Serialize;
+
+use crate::{
+    NEIGHBORHOOD_SIZE,
+    db::SongData,
+    error::{Error, Result},
+    fingerprint::{Fingerprint, FingerprintData},
+    store::{FingerprintStore, unique_sorted},
+    utils::longest_increasing_subsequence,
+};
+
+/*
+    Ranking of landmark matches. Only addresses and song ids come from the store, so every backend
+    implementing `FingerprintStore` is searched the same way.
+*/
+
+// Speed factors outside of this range are treated as accidental matches
+const MIN_SPEED: f32 = 0.8;
+const MAX_SPEED: f32 = 1.25;
+// Width of the speed histogram bins
+const SPEED_BIN: f32 = 0.01;
+// Width of the offset histogram bins in milliseconds
+pub(crate) const OFFSET_BIN: f32 = 100.0;
+// Matches a neighborhood needs however many of its addresses are skipped
+const MIN_KEPT_ADDRESSES: usize = 3;
+// Share of the query a match has to cover above the background to get a confidence of 0.5
+const HALF_CONFIDENCE_COVERAGE: f32 = 0.05;
+
+#[derive(Debug, Serialize)]
+pub struct RankingData {
+    pub song_id: i32,
+    pub data: SongData,
+    pub score: i32,
+    // How much faster the query plays than the reference, 1.0 outside of invariant mode
+    pub speed: f32,
+    pub position: MatchPosition,
+    // Between 0 and 1, comparable across query lengths unlike the score
+    pub confidence: f32,
+}
+
+#[derive(Clone, Copy)]
+pub struct RankingOptions<'a> {
+    pub rank: usize,
+    // Scores landmark matches, invariant searches always vote on time offsets
+    pub scorer: &'a dyn Scorer,
+    // Songs below this confidence are left out, so nothing is returned when no song really matches
+    pub min_confidence: f32,
+    // Landmark searches skip addresses stored more often than this, None keeps them all
+    pub max_postings: Option<usize>,
+    // Landmark searches only score the songs with the most neighborhoods, None scores all that can reach `rank`
+    pub max_candidates: Option<usize>,
+}
+
+impl RankingOptions<'_> {
+    // Invariant and Philips searches have neither, asking for them there is a mistake
+    pub fn check_landmark_only(&self, index: &str) -> Result<()> {
+        if self.max_postings.is_some() || self.max_candidates.is_some() {
+            return Err(Error::Unsupported(format!(
+                "--max-postings and --max-candidates only apply to landmark indexes, not to {} ones",
+                index
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for RankingOptions<'_> {
+    fn default() -> Self {
+        RankingOptions {
+            rank: 10,
+            scorer: &LisScorer,
+            min_confidence: 0.0,
+            max_postings: None,
+            max_candidates: None,
+        }
+    }
+}
+
+// Where the query matched the reference, all times in seconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct MatchPosition {
+    // Time of the reference at which the query starts, negative if the query starts before the reference
+    pub offset: f32,
+    // Matched part of the reference
+    pub reference_start: f32,
+    pub reference_end: f32,
+    // Matched part of the query, from the start of the query
+    pub query_start: f32,
+    pub query_end: f32,
+}
+
+/*
+    Locates a match from the (query time, reference time) pairs, in milliseconds, of the matches in the
+    winning region of a song. The offset is the median of `reference - speed * query`, pairs further
+    than OFFSET_BIN from it are left out of the spans.
+*/
+fn match_position(pairs: &[(f32, f32)], speed: f32) -> MatchPosition {
+    let mut offsets = pairs
+        .iter()
+        .map(|&(query, reference)| reference - speed * query)
+        .collect::<Vec<_>>();
+    if offsets.is_empty() {
+        return MatchPosition::default();
+    }
+    offsets.sort_unstable_by(f32::total_cmp);
+    let offset = offsets[offsets.len() / 2];
+
+    let mut position = MatchPosition {
+        offset,
+        reference_start: f32::MAX,
+        reference_end: f32::MIN,
+        query_start: f32::MAX,
+        query_end: f32::MIN,
+    };
+    for &(query, reference) in pairs {
+        if (reference - speed * query - offset).abs() > OFFSET_BIN {
+            continue;
+        }
+        position.reference_start = position.reference_start.min(reference);
+        position.reference_end = position.reference_end.max(reference);
+        position.query_start = position.query_start.min(query);
+        position.query_end = position.query_end.max(query);
+    }
+    MatchPosition {
+        offset: position.offset / 1000.0,
+        reference_start: position.reference_start / 1000.0,
+        reference_end: position.reference_end / 1000.0,
+        query_start: position.query_start / 1000.0,
+        query_end: position.query_end / 1000.0,
+    }
+}
+
+// A reference neighborhood lined up with a query neighborhood
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Couples {
+    pub anchor_address: u32,
+    pub anchor_time: u32,
+    pub song_id: i32,
+    pub query_time: u32,
+}
+
+fn addresses(fingerprints: &[Fingerprint]) -> Vec<u32> {
+    unique_sorted(fingerprints.iter().map(|fp| fp.address))
+}
+
+// Anchor times of a query neighborhood and of the reference neighborhood it matched, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Match {
+    pub query_time: u32,
+    pub reference_time: u32,
+}
+
+/*
+    Checks the temporal coherence of the matches of one song and turns them into a score. Only used
+    by landmark searches, invariant ones have to estimate the speed first and always vote on offsets.
+*/
+pub trait Scorer {
+    // Score and position of a song, `query_duration` is the span of the query's anchors
+    fn score(&self, matches: &mut [Match], query_duration: u32) -> (usize, MatchPosition);
+}
+
+/*
+    After get the fingerprints for each song from database, we need to verify their temporal coherence with the sample.
+
+    We'll implement a Longest Increasing Subsequence (LIS) algorithm on each song's fingerprint to ensure the chronological
+    order of peaks matches our sample's pattern.
+
+    To guard against false positives from random matching peaks, we'll employ a sliding window technique. This window,
+    sized to match our sample duration, will move across the LIS results. The maximum number of matching peaks detected
+    within any window position will serve as our relevance score for that song.
+*/
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LisScorer;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LisWindow {
+    pub lis_length: usize,
+    // Matches of the LIS within the best window, the score of the song
+    pub score: usize,
+    // Reference times of the first and last of them in milliseconds
+    pub start: u32,
+    pub end: u32,
+}
+
+impl LisScorer {
+    pub fn window(&self, matches: &mut [Match], query_duration: u32) -> LisWindow {
+        // Reversed reference times keep a query anchor from being used twice in the LIS
+        matches.sort_unstable_by_key(|m| (m.query_time, Reverse(m.reference_time)));
+        let times = matches
+            .iter()
+            .map(|m| m.reference_time)
+            .collect::<Box<[u32]>>();
+        let lis = longest_increasing_subsequence(&times);
+
+        let mut l: usize = 0;
+        let mut window = LisWindow {
+            lis_length: lis.len(),
+            ..Default::default()
+        };
+        for r in 0..lis.len() {
+            while lis[r] - lis[l] > query_duration {
+                l += 1;
+            }
+            if r - l + 1 > window.score {
+                window.score = r - l + 1;
+                window.start = lis[l];
+                window.end = lis[r];
+            }
+        }
+        window
+    }
+
+    // The matches of the best window tell where the query sits in the song
+    pub fn position(&self, matches: &[Match], window: &LisWindow) -> MatchPosition {
+        let pairs = matches
+            .iter()
+            .filter(|m| (window.start..=window.end).contains(&m.reference_time))
+            .map(|m| (m.query_time as f32, m.reference_time as f32))
+            .collect::<Vec<_>>();
+        match_position(&pairs, 1.0)
+    }
+}
+
+impl Scorer for LisScorer {
+    fn score(&self, matches: &mut [Match], query_duration: u32) -> (usize, MatchPosition) {
+        let window = self.window(matches, query_duration);
+        (window.score, self.position(matches, &window))
+    }
+}
+
+/*
+    The classic scorer: a copy of the reference keeps reference_time - query_time constant, so every
+    match votes for its offset and the song scores the votes of the peak bin. Offsets are binned by
+    `tolerance_ms` and the neighbouring bins count too, so that a peak split by a bin edge isn't halved.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct HistogramScorer {
+    pub tolerance_ms: u32,
+}
+
+impl Default for HistogramScorer {
+    fn default() -> Self {
+        HistogramScorer {
+            tolerance_ms: OFFSET_BIN as u32,
+        }
+    }
+}
+
+impl Scorer for HistogramScorer {
+    fn score(&self, matches: &mut [Match], _query_duration: u32) -> (usize, MatchPosition) {
+        let bin = |m: &Match| {
+            (m.reference_time as i64 - m.query_time as i64)
+                .div_euclid(self.tolerance_ms.max(1) as i64)
+        };
+        let mut histogram: HashMap<i64, usize> = HashMap::new();
+        for m in matches.iter() {
+            *histogram.entry(bin(m)).or_default() += 1;
+        }
+        let votes = |bin: i64| {
+            (bin - 1..=bin + 1)
+                .map(|bin| histogram.get(&bin).copied().unwrap_or(0))
+                .sum::<usize>()
+        };
+        let Some((score, best_bin)) = histogram
+            .keys()
+            .map(|&bin| (votes(bin), bin))
+            .max_by_key(|&(votes, bin)| (votes, -bin))
+        else {
+            return (0, MatchPosition::default());
+        };
+
+        let pairs = matches
+            .iter()
+            .filter(|m| (bin(m) - best_bin).abs() <= 1)
+            .map(|m| (m.query_time as f32, m.reference_time as f32))
+            .collect::<Vec<_>>();
+        (score, match_position(&pairs, 1.0))
+    }
+}
+
+/*
+    Joins the query against the store. A reference neighborhood matches a query neighborhood when
+    all of its fingerprints are found in the query with the same anchor time. Every occurrence of an
+    address in the query is kept, so a passage repeated in the query gives a time pair for each
+    repetition.
+*/
+pub(crate) fn join_matches<S: FingerprintStore + ?Sized>(
+    store: &S,
+    fingerprints: &[Fingerprint],
+) -> Result<HashMap<i32, Vec<Match>>> {
+    join_matches_with(store, fingerprints, None, |_| {})
+}
+
+struct Join {
+    occurrences: HashMap<u32, Vec<u32>>,
+    // Count matches to identify complete neighborhoods
+    match_counts: HashMap<Couples, usize>,
+    song_matches: HashMap<i32, Vec<Match>>,
+    // Completeness is only known once every skipped address is known, see `join_matches_with`
+    deferred: bool,
+}
+
+impl Join {
+    fn add(&mut self, row: &FingerprintData) {
+        let fingerprint = &row.fingerprint;
+        let Some(query_times) = self.occurrences.get(&fingerprint.address) else {
+            return;
+        };
+        for &query_time in query_times {
+            let key = Couples {
+                anchor_address: fingerprint.anchor_address,
+                anchor_time: fingerprint.anchor_time,
+                song_id: row.song_id,
+                query_time,
+            };
+            let count = self.match_counts.entry(key).or_insert(0);
+            *count += 1;
+
+            if *count == NEIGHBORHOOD_SIZE && !self.deferred {
+                self.song_matches
+                    .entry(row.song_id)
+                    .or_default()
+                    .push(Match {
+                        query_time,
+                        reference_time: fingerprint.anchor_time,
+                    });
+            }
+        }
+    }
+}
+
+/*
+    Same as `join_matches`, `on_hit` also sees every row sharing an address with the query. Addresses
+    stored more than `max_postings` times are left out, such low-entropy hashes are found in most
+    songs and cost more than they tell. Rows come grouped by address, so only one posting list at a
+    time is held back until its length is known.
+
+    A neighborhood holding a skipped address could never be complete, so at a query time with skipped
+    addresses as many fewer matches are needed, but never fewer than MIN_KEPT_ADDRESSES.
+*/
+fn join_matches_with<S: FingerprintStore + ?Sized>(
+    store: &S,
+    fingerprints: &[Fingerprint],
+    max_postings: Option<usize>,
+    mut on_hit: impl FnMut(&FingerprintData),
+) -> Result<HashMap<i32, Vec<Match>>> {
+    let mut occurrences: HashMap<u32, Vec<u32>> = HashMap::new();
+    for fp in fingerprints {
+        occurrences
+            .entry(fp.address)
+            .or_default()
+            .push(fp.anchor_time);
+    }
+    for query_times in occurrences.values_mut() {
+        query_times.sort_unstable();
+        query_times.dedup();
+    }
+
+    let mut join = Join {
+        occurrences,
+        match_counts: HashMap::new(),
+        song_matches: HashMap::new(),
+        deferred: max_postings.is_some(),
+    };
+    let Some(max_postings) = max_postings else {
+        store.for_each_match(&addresses(fingerprints), &mut |row| {
+            on_hit(&row);
+            join.add(&row);
+            Ok(())
+        })?;
+        return Ok(join.song_matches);
+    };
+
+    let mut skipped = HashSet::new();
+    let mut posting: Vec<FingerprintData> = Vec::new();
+    let mut flush = |posting: &mut Vec<FingerprintData>, join: &mut Join| {
+        if posting.len() <= max_postings {
+            for row in posting.iter() {
+                on_hit(row);
+                join.add(row);
+            }
+        } else if let Some(row) = posting.first() {
+            skipped.insert(row.fingerprint.address);
+        }
+        posting.clear();
+    };
+    store.for_each_match(&addresses(fingerprints), &mut |row| {
+        if posting
+            .first()
+            .is_some_and(|first| first.fingerprint.address != row.fingerprint.address)
+        {
+            flush(&mut posting, &mut join);
+        }
+        posting.push(row);
+        Ok(())
+    })?;
+    flush(&mut posting, &mut join);
+
+    // Skipped addresses of the query at every anchor time
+    let mut skipped_at: HashMap<u32, HashSet<u32>> = HashMap::new();
+    for fp in fingerprints
+        .iter()
+        .filter(|fp| skipped.contains(&fp.address))
+    {
+        skipped_at
+            .entry(fp.anchor_time)
+            .or_default()
+            .insert(fp.address);
+    }
+    for (key, count) in join.match_counts {
+        let skipped = skipped_at.get(&key.query_time).map_or(0, HashSet::len);
+        if count
+            >= NEIGHBORHOOD_SIZE
+                .saturating_sub(skipped)
+                .max(MIN_KEPT_ADDRESSES)
+        {
+            join.song_matches
+                .entry(key.song_id)
+                .or_default()
+                .push(Match {
+                    query_time: key.query_time,
+                    reference_time: key.anchor_time,
+                });
+        }
+    }
+    Ok(join.song_matches)
+}
+
+/*
+    Turns scores into confidences. Most candidate songs of a query only match by chance, so the mean
+    score of the other candidates is the background a real match has to stand out from. What is left
+    is divided by the best score the query could reach, which makes it comparable across query lengths.
+*/
+fn confidences(scores: &[usize], capacity: usize) -> Vec<f32> {
+    let total = scores.iter().sum::<usize>() as f32;
+    let others = scores.len().saturating_sub(1).max(1) as f32;
+    scores
+        .iter()
+        .map(|&score| {
+            let background = (total - score as f32) / others;
+            coverage_confidence((score as f32 - background).max(0.0) / capacity.max(1) as f32)
+        })
+        .collect()
+}
+
+// Maps the share of the query a song covers to 0..1
+pub(crate) fn coverage_confidence(coverage: f32) -> f32 {
+    coverage / (coverage + HALF_CONFIDENCE_COVERAGE)
+}
+
+// (song_id, score, speed, position) of a candidate song
+type Scored = (i32, usize, f32, MatchPosition);
+
+/*
+    Attaches the confidences, drops the songs below `min_confidence` and keeps the best `rank`.
+    `unscored` are the complete neighborhoods of the candidates skipped with `max_candidates`, the
+    most each could have scored. They still count in the background, so skipping makes confidences
+    a little lower, never higher.
+*/
+fn rank_songs<S: FingerprintStore + ?Sized>(
+    store: &S,
+    scored: Vec<Scored>,
+    unscored: &[usize],
+    capacity: usize,
+    options: &RankingOptions,
+) -> Vec<RankingData> {
+    let mut scores = scored.iter().map(|s| s.1).collect::<Vec<_>>();
+    scores.extend(unscored);
+    let mut result = scored
+        .into_iter()
+        .zip(confidences(&scores, capacity))
+        .filter(|(_, confidence)| *confidence >= options.min_confidence)
+        .collect::<Vec<_>>();
+    result.sort_unstable_by_key(|((_, score, _, _), _)| Reverse(*score));
+    result.truncate(options.rank);
+
+    result
+        .into_iter()
+        .filter_map(|((song_id, score, speed, position), confidence)| {
+            store.get_song(song_id).ok().map(|data| RankingData {
+                song_id,
+                data,
+                score: score as i32,
+                speed,
+                position,
+                confidence,
+            })
+        })
+        .collect()
+}
+
+// Span of the anchor times, fingerprint files may list them in any order
+fn query_duration(fingerprints: &[Fingerprint]) -> u32 {
+    let times = fingerprints.iter().map(|fp| fp.anchor_time);
+    match (times.clone().min(), times.max()) {
+        (Some(first), Some(last)) => last - first,
+        _ => 0,
+    }
+}
+
+pub fn search<S: FingerprintStore + ?Sized>(
+    store: &S,
+    fingerprints: Vec<Fingerprint>,
+    options: &RankingOptions,
+) -> Result<Vec<RankingData>> {
+    if fingerprints.is_empty() {
+        return Ok(Vec::new());
+    }
+    let sample_duration = query_duration(&fingerprints);
+    let song_matches = join_matches_with(store, &fingerprints, options.max_postings, |_| {})?;
+
+    /*
+        With `max_candidates`, candidates are scored from the most voted down, and scoring stops
+        after that many of them, at the cost of some recall. A song can't score more than its number
+        of complete neighborhoods, so the ones that can't reach the top `rank` any more are skipped
+        as well. Without it every candidate is scored and counts in the confidences by its score.
+    */
+    let mut candidates = song_matches.into_iter().collect::<Vec<_>>();
+    candidates.sort_unstable_by_key(|(song_id, matches)| (Reverse(matches.len()), *song_id));
+
+    let mut result = Vec::new();
+    let mut top = BinaryHeap::new();
+    let mut unscored = Vec::new();
+    for (song_id, mut matches) in candidates {
+        if let Some(max_candidates) = options.max_candidates {
+            let votes = matches.len();
+            let out_of_reach = top.len() == options.rank
+                && top.peek().is_some_and(|&Reverse(lowest)| votes < lowest);
+            if out_of_reach || result.len() >= max_candidates {
+                unscored.push(votes);
+                continue;
+            }
+        }
+        let (score, position) = options.scorer.score(&mut matches, sample_duration);
+        result.push((song_id, score, 1.0, position));
+        top.push(Reverse(score));
+        if top.len() > options.rank {
+            top.pop();
+        }
+    }
+
+    // Every anchor of the query can match once
+    let anchors = unique_sorted(fingerprints.iter().map(|fp| fp.anchor_time)).len();
+    Ok(rank_songs(store, result, &unscored, anchors, options))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Explanation {
+    pub song_id: i32,
+    pub song: SongData,
+    // Rows of the song sharing an address with the query
+    pub address_hits: usize,
+    // Neighborhoods of the song found complete in the query
+    pub neighborhood_matches: usize,
+    pub window: LisWindow,
+    pub position: MatchPosition,
+    // Every complete neighborhood, in query order
+    pub matches: Vec<Match>,
+}
+
+// Details of how `song_id` got its `LisScorer` score in a landmark search, to diagnose false positives
+pub fn explain<S: FingerprintStore + ?Sized>(
+    store: &S,
+    fingerprints: &[Fingerprint],
+    song_id: i32,
+) -> Result<Explanation> {
+    let song = store.get_song(song_id)?;
+    let mut address_hits = 0;
+    let mut song_matches = join_matches_with(store, fingerprints, None, |row| {
+        if row.song_id == song_id {
+            address_hits += 1;
+        }
+    })?;
+    let mut matches = song_matches.remove(&song_id).unwrap_or_default();
+
+    let window = LisScorer.window(&mut matches, query_duration(fingerprints));
+    let position = LisScorer.position(&matches, &window);
+    Ok(Explanation {
+        song_id,
+        song,
+        address_hits,
+        neighborhood_matches: matches.len(),
+        window,
+        position,
+        matches,
+    })
+}
+
+pub fn search_invariant<S: FingerprintStore + ?Sized>(
+    store: &S,
+    fingerprints: Vec<Fingerprint>,
+    options: &RankingOptions,
+) -> Result<Vec<RankingData>> {
+    options.check_landmark_only("invariant")?;
+    if fingerprints.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // An address can occur many times in the query, keep every (anchor_time, span) pair
+    let mut query_map: HashMap<u32, Vec<(u32, u32)>> = HashMap::new();
+    for fp in &fingerprints {
+        query_map
+            .entry(fp.address)
+            .or_default()
+            .push((fp.anchor_time, fp.anchor_address));
+    }
+
+    // (speed, reference time, query time) of every match, grouped by song
+    let mut song_matches: HashMap<i32, Vec<(f32, f32, f32)>> = HashMap::new();
+    store.for_each_match(&addresses(&fingerprints), &mut |row| {
+        let Some(occurrences) = query_map.get(&row.fingerprint.address) else {
+            return Ok(());
+        };
+        for &(query_time, query_span) in occurrences {
+            if query_span == 0 {
+                continue;
+            }
+            let speed = row.fingerprint.anchor_address as f32 / query_span as f32;
+            if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+                continue;
+            }
+            song_matches.entry(row.song_id).or_default().push((
+                speed,
+                row.fingerprint.anchor_time as f32,
+                query_time as f32,
+            ));
+        }
+        Ok(())
+    })?;
+
+    /*
+        Every match votes for a speed factor. We take the most voted speed, then among the matches
+        agreeing with it we check that reference_time - speed * query_time stays constant, which is
+        what a sped up copy of the reference looks like. The size of the largest offset bin is the score.
+    */
+    let mut result = Vec::with_capacity(song_matches.len());
+    for (song_id, matches) in song_matches {
+        let mut speed_histogram: HashMap<i32, usize> = HashMap::new();
+        for &(speed, _, _) in &matches {
+            *speed_histogram
+                .entry((speed / SPEED_BIN).round() as i32)
+                .or_default() += 1;
+        }
+        let Some(best_speed_bin) = speed_histogram
+            .iter()
+            .max_by_key(|&(bin, count)| (*count, -bin))
+            .map(|(bin, _)| *bin)
+        else {
+            continue;
+        };
+
+        let agreeing = matches
+            .into_iter()
+            .filter(|(speed, _, _)| {
+                ((speed / SPEED_BIN).round() as i32 - best_speed_bin).abs() <= 1
+            })
+            .collect::<Vec<_>>();
+        let speed = agreeing.iter().map(|(speed, _, _)| speed).sum::<f32>() / agreeing.len() as f32;
+
+        let offset_bin = |ref_time: f32, query_time: f32| {
+            ((ref_time - speed * query_time) / OFFSET_BIN).round() as i64
+        };
+        let mut offset_histogram: HashMap<i64, usize> = HashMap::new();
+        for &(_, ref_time, query_time) in &agreeing {
+            *offset_histogram
+                .entry(offset_bin(ref_time, query_time))
+                .or_default() += 1;
+        }
+        let Some((best_offset_bin, score)) = offset_histogram
+            .into_iter()
+            .max_by_key(|&(bin, count)| (count, -bin))
+        else {
+            continue;
+        };
+
+        let pairs = agreeing
+            .iter()
+            .filter(|&&(_, ref_time, query_time)| {
+                offset_bin(ref_time, query_time) == best_offset_bin
+            })
+            .map(|&(_, ref_time, query_time)| (query_time, ref_time))
+            .collect::<Vec<_>>();
+        result.push((song_id, score, speed, match_position(&pairs, speed)));
+    }
+
+    // Every fingerprint of the query votes once
+    Ok(rank_songs(store, result, &[], fingerprints.len(), options))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{
+        db::DbClient,
+        error::Error,
+        fingerprint::generate_fingerprint,
+        spectrogram::Peak,
+        test_utils::{TIME_BASE, WHOLE_MS_FRAMES, excerpt, peaks},
+    };
+
+    #[test]
+    fn test_explain() {
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for seed in 1..=2 {
+            let song_id = store.insert_song(&SongData::default()).unwrap();
+            let fingerprints = generate_fingerprint(peaks(seed, 0..200), TIME_BASE);
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+        let query = generate_fingerprint(excerpt(2, 50..100, 0), TIME_BASE);
+        let options = RankingOptions {
+            rank: 1,
+            scorer: &LisScorer,
+            min_confidence: 0.0,
+            ..Default::default()
+        };
+        let result = search(&store, query.clone(), &options).unwrap();
+
+        // The explanation of the winner agrees with its search result
+        let explanation = explain(&store, &query, 2).unwrap();
+        assert_eq!(explanation.window.score as i32, result[0].score);
+        assert_eq!(explanation.position, result[0].position);
+        assert!(explanation.window.lis_length <= explanation.neighborhood_matches);
+        assert!(explanation.address_hits >= explanation.neighborhood_matches * NEIGHBORHOOD_SIZE);
+        assert!(explanation.matches.is_sorted_by_key(|m| m.query_time));
+        let start = TIME_BASE.frame_to_ms(50) as f32 / 1000.0;
+        assert!((explanation.position.offset - start).abs() < 0.1);
+
+        let other = explain(&store, &query, 1).unwrap();
+        assert!(other.window.score < explanation.window.score);
+        assert!(matches!(
+            explain(&store, &query, 3),
+            Err(Error::SongNotFound(3))
+        ));
+    }
+
+    #[test]
+    fn test_unordered_fingerprint_file() {
+        use crate::{
+            fingerprint::{Algorithm, FingerprintMode},
+            format::{FingerprintConfig, FingerprintFile, Fingerprints},
+        };
+
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for seed in 1..=2 {
+            let song_id = store.insert_song(&SongData::default()).unwrap();
+            let fingerprints = generate_fingerprint(peaks(seed, 0..200), TIME_BASE);
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+        let query = generate_fingerprint(excerpt(2, 50..100, 0), TIME_BASE);
+        let options = RankingOptions {
+            rank: 1,
+            ..Default::default()
+        };
+        let ordered = search(&store, query.clone(), &options).unwrap();
+
+        // Files store anchor times as signed deltas, a reversed query reads back as written
+        let mut reversed = query;
+        reversed.reverse();
+        let file = FingerprintFile::new(
+            FingerprintConfig::new(Algorithm::Landmark, FingerprintMode::Landmark, 11025),
+            Fingerprints::Landmark(reversed),
+        );
+        let mut bytes = Vec::new();
+        file.write_binary(&mut bytes).unwrap();
+        let Fingerprints::Landmark(unordered) = FingerprintFile::read_binary(&mut bytes.as_slice())
+            .unwrap()
+            .fingerprints
+        else {
+            panic!("a landmark file reads back as landmarks");
+        };
+        let result = search(&store, unordered, &options).unwrap();
+        assert_eq!(result[0].song_id, ordered[0].song_id);
+        assert_eq!(result[0].score, ordered[0].score);
+        assert_eq!(result[0].position, ordered[0].position);
+    }
+
+    #[test]
+    fn test_search_through_store() {
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for (seed, title) in [(1, "one"), (2, "two")] {
+            let song_id = store
+                .insert_song(&SongData {
+                    title: title.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            let fingerprints = generate_fingerprint(peaks(seed, 0..200), TIME_BASE);
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+
+        let query = generate_fingerprint(peaks(2, 50..100), TIME_BASE);
+        let result = search(
+            &store,
+            query.clone(),
+            &RankingOptions {
+                rank: 10,
+                scorer: &LisScorer,
+                min_confidence: 0.0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(result[0].data.title, "two");
+        assert!(
+            result
+                .get(1)
+                .is_none_or(|other| other.score < result[0].score)
+        );
+
+        assert!(
+            search(
+                &store,
+                Vec::new(),
+                &RankingOptions {
+                    rank: 10,
+                    scorer: &LisScorer,
+                    min_confidence: 0.0,
+                    ..Default::default()
+                }
+            )
+            .unwrap()
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_confidence() {
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for seed in 1..=3 {
+            let song_id = store.insert_song(&SongData::default()).unwrap();
+            let fingerprints = generate_fingerprint(peaks(seed, 0..200), TIME_BASE);
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+        let options = RankingOptions {
+            rank: 10,
+            scorer: &LisScorer,
+            min_confidence: 0.5,
+            ..Default::default()
+        };
+
+        let result = search(
+            &store,
+            generate_fingerprint(peaks(2, 50..100), TIME_BASE),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(result.len(), 1);
+        assert!(result[0].confidence > 0.5 && result[0].confidence <= 1.0);
+
+        let unknown = generate_fingerprint(peaks(9, 50..100), TIME_BASE);
+        assert!(search(&store, unknown, &options).unwrap().is_empty());
+
+        assert_eq!(confidences(&[0, 0], 10), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_histogram_scorer_needs_a_constant_offset() {
+        // Ten matches at a constant offset of 5s, then twenty in order but drifting away from each other
+        let mut matches = (0..10)
+            .map(|i| Match {
+                query_time: i * 100,
+                reference_time: 5000 + i * 100,
+            })
+            .chain((0..20).map(|i| Match {
+                query_time: 1000 + i * 100,
+                reference_time: 20000 + i * 150,
+            }))
+            .collect::<Vec<_>>();
+
+        let (lis_score, _) = LisScorer.score(&mut matches.clone(), 3000);
+        assert_eq!(lis_score, 20);
+        let (score, position) = HistogramScorer::default().score(&mut matches, 3000);
+        assert_eq!(score, 10);
+        assert!((position.offset - 5.0).abs() < 0.01);
+        assert_eq!(
+            HistogramScorer::default().score(&mut [], 0),
+            (0, MatchPosition::default())
+        );
+    }
+
+    #[test]
+    fn test_invariant_search_estimates_speed() {
+        use crate::fingerprint::generate_invariant_fingerprint;
+
+        // A peak every `step` frames at pseudo random bins, step 10 plays the step 11 copy 1.1 times faster
+        let song = |seed: u32, step: u32| {
+            (0..60)
+                .map(|i| Peak {
+                    frame: i * step,
+                    freq: (4 + ((i + seed * 1000).wrapping_mul(2654435761) >> 28)) * (21 - step),
+                    magnitude: 1.0,
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for (seed, title) in [(1, "one"), (2, "two")] {
+            let song_id = store
+                .insert_song(&SongData {
+                    title: title.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            let fingerprints = generate_invariant_fingerprint(song(seed, 11), TIME_BASE);
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+
+        let query = generate_invariant_fingerprint(song(2, 10), TIME_BASE);
+        let reference = generate_invariant_fingerprint(song(2, 11), TIME_BASE);
+        assert_eq!(
+            addresses(&query),
+            addresses(&reference),
+            "frequency and time ratios don't change with the speed"
+        );
+        let result = search_invariant(&store, query, &RankingOptions::default()).unwrap();
+        assert_eq!(result[0].data.title, "two");
+        assert!(
+            (result[0].speed - 1.1).abs() < 0.02,
+            "speed {}",
+            result[0].speed
+        );
+    }
+
+    #[test]
+    fn test_match_position() {
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        let song_id = store.insert_song(&SongData::default()).unwrap();
+        let fingerprints = generate_fingerprint(peaks(1, 0..300), TIME_BASE);
+        store.insert_fingerprints(song_id, &fingerprints).unwrap();
+
+        let query = generate_fingerprint(excerpt(1, 100..200, 0), TIME_BASE);
+        let position = search(
+            &store,
+            query,
+            &RankingOptions {
+                rank: 1,
+                scorer: &LisScorer,
+                min_confidence: 0.0,
+                ..Default::default()
+            },
+        )
+        .unwrap()[0]
+            .position;
+
+        let start = TIME_BASE.frame_to_ms(100) as f32 / 1000.0;
+        assert!((position.offset - start).abs() < 0.05);
+        assert!(position.reference_start >= start - 0.05);
+        assert!(position.reference_end <= TIME_BASE.frame_to_ms(200) as f32 / 1000.0);
+        assert!(position.query_start < 0.5);
+        assert!(
+            (position.reference_end
+                - position.reference_start
+                - (position.query_end - position.query_start))
+                .abs()
+                < 0.05
+        );
+    }
+
+    #[test]
+    fn test_repeated_query_passage() {
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        let song_id = store.insert_song(&SongData::default()).unwrap();
+        let fingerprints = generate_fingerprint(peaks(1, 0..300), TIME_BASE);
+        store.insert_fingerprints(song_id, &fingerprints).unwrap();
+
+        let passage = |start: u32| excerpt(1, 100..140, start);
+        let once = generate_fingerprint(passage(0), TIME_BASE);
+        let twice =
+            generate_fingerprint([passage(0), passage(WHOLE_MS_FRAMES)].concat(), TIME_BASE);
+        let once = &join_matches(&store, &once).unwrap()[&song_id];
+        let twice = &join_matches(&store, &twice).unwrap()[&song_id];
+
+        // Both repetitions line up with the same part of the song
+        let second = TIME_BASE.frame_to_ms(WHOLE_MS_FRAMES);
+        let (first, repeated): (Vec<&Match>, Vec<&Match>) =
+            twice.iter().partition(|m| m.query_time < second);
+        assert_eq!(repeated.len(), once.len());
+        assert!(!first.is_empty());
+        let offset = TIME_BASE.frame_to_ms(100) as i64;
+        for m in first {
+            assert!((m.reference_time as i64 - m.query_time as i64 - offset).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_stop_hashes_and_early_termination() {
+        // The same chord at the end of every song gives addresses stored far more often than the others
+        let chord = |frames: std::ops::Range<u32>| {
+            frames.flat_map(|frame| {
+                [100, 300].map(|freq| Peak {
+                    frame,
+                    freq,
+                    magnitude: 1.0,
+                })
+            })
+        };
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for (seed, title) in [(1, "one"), (2, "two"), (3, "three")] {
+            let song_id = store
+                .insert_song(&SongData {
+                    title: title.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            let song_peaks = peaks(seed, 0..200).into_iter().chain(chord(200..260));
+            let fingerprints = generate_fingerprint(song_peaks.collect(), TIME_BASE);
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+
+        let query_peaks = peaks(2, 150..200).into_iter().chain(chord(200..230));
+        let query = generate_fingerprint(query_peaks.collect(), TIME_BASE);
+        let exact = search(&store, query.clone(), &RankingOptions::default()).unwrap();
+        assert_eq!(exact[0].data.title, "two");
+        assert_eq!(exact.len(), 3);
+        // Without max_candidates every song is scored, the confidences don't depend on `rank`
+        let first = RankingOptions {
+            rank: 1,
+            ..Default::default()
+        };
+        let best = search(&store, query.clone(), &first).unwrap();
+        assert_eq!(best[0].confidence, exact[0].confidence);
+
+        for (max_postings, max_candidates) in
+            [(Some(20), None), (None, Some(1)), (Some(20), Some(1))]
+        {
+            let result = search(
+                &store,
+                query.clone(),
+                &RankingOptions {
+                    max_postings,
+                    max_candidates,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(result[0].data.title, "two");
+            assert!((result[0].position.offset - exact[0].position.offset).abs() < 0.05);
+        }
+
+        // With the chord skipped, the other songs share nothing with the query
+        let result = search(
+            &store,
+            query,
+            &RankingOptions {
+                max_postings: Some(20),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(result.len(), 1);
+
+        let skipping = RankingOptions {
+            max_candidates: Some(1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            search_invariant(&store, Vec::new(), &skipping),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
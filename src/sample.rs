@@ -1,6 +1,6 @@
 use std::{fs::File, io::BufReader, path::PathBuf};
 
-use anyhow::Result;
+use crate::error::{Error, IoContext, Result};
 
 pub struct Sample {
     pub sample: Vec<f32>,
@@ -8,6 +8,13 @@ pub struct Sample {
 }
 
 impl Sample {
+    pub fn duration_ms(&self) -> u32 {
+        if self.sample_rate == 0 {
+            return 0;
+        }
+        (self.sample.len() as u64 * 1000 / self.sample_rate as u64) as u32
+    }
+
     pub fn low_pass_filter(&self, cutoff_freq: f32) -> Sample {
         // IIR low pass filter
         // y[n] = alpha * x[n] + (1.0 - alpha) * y[n-1]
@@ -55,20 +62,35 @@ impl Sample {
     }
 
     pub fn read_mp3(path: &PathBuf) -> Result<Self> {
-        let file = File::open(path)?;
+        let file = File::open(path).with_path(path)?;
         let reader = BufReader::new(file);
         let mut decoder = minimp3::Decoder::new(reader);
 
         let mut mono_samples = Vec::new();
         let mut sampling_rate = 0;
 
-        while let Ok(minimp3::Frame {
-            data,
-            sample_rate,
-            channels,
-            ..
-        }) = decoder.next_frame()
-        {
+        loop {
+            let minimp3::Frame {
+                data,
+                sample_rate,
+                channels,
+                ..
+            } = match decoder.next_frame() {
+                Ok(frame) => frame,
+                Err(minimp3::Error::Eof) => break,
+                Err(minimp3::Error::Io(source)) => {
+                    return Err(Error::Io {
+                        path: Some(path.clone()),
+                        source,
+                    });
+                }
+                Err(err) => {
+                    return Err(Error::Decode {
+                        path: path.clone(),
+                        reason: err.to_string(),
+                    });
+                }
+            };
             if sampling_rate == 0 {
                 sampling_rate = sample_rate;
             }
@@ -86,9 +108,21 @@ impl Sample {
                         mono_samples.push(avg);
                     }
                 }
-                _ => panic!("Unsupported number of channels: {}", channels),
+                _ => {
+                    return Err(Error::Decode {
+                        path: path.clone(),
+                        reason: format!("unsupported number of channels: {}", channels),
+                    });
+                }
             }
         }
+        // The decoder skips anything that isn't an MPEG frame, so other files end up here
+        if sampling_rate == 0 {
+            return Err(Error::Decode {
+                path: path.clone(),
+                reason: "no MPEG audio frames found".to_string(),
+            });
+        }
         Ok(Sample {
             sample: mono_samples,
             sample_rate: sampling_rate as usize,
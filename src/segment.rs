@@ -0,0 +1,208 @@
+use std::cmp::Reverse;
+
+use crate::{
+    db::SongData,
+    error::Result,
+    fingerprint::Fingerprint,
+    ranking::{OFFSET_BIN, coverage_confidence, join_matches},
+    store::{FingerprintStore, unique_sorted},
+};
+
+/*
+    Segmentation of queries that play several songs one after the other, such as DJ mixes or radio
+    recordings. The matches of a song that keep the same offset between the query and the song form
+    a segment, so a song played twice gives two segments. Where segments overlap in the query, the
+    one with the most matches keeps the overlapped part.
+*/
+
+// Matches further apart in the query are not the same segment
+const MAX_GAP_MS: u32 = 5000;
+// Fewer matches at one offset happen by chance
+const MIN_SEGMENT_MATCHES: usize = 8;
+
+#[derive(Debug)]
+pub struct Segment {
+    pub data: SongData,
+    // Part of the query the song is played in, in seconds
+    pub query_start: f32,
+    pub query_end: f32,
+    // Time of the song at which the query starts, the segment starts at query_start + reference_offset in the song
+    pub reference_offset: f32,
+    pub score: usize,
+    pub confidence: f32,
+}
+
+struct Candidate {
+    song_id: i32,
+    offset: i64,
+    // Sorted query times of the matches
+    query_times: Vec<u32>,
+}
+
+// Splits sorted query times wherever the next one is too far away or `split` says so
+fn runs(query_times: &[u32], split: impl Fn(u32, u32) -> bool) -> Vec<Vec<u32>> {
+    let mut runs: Vec<Vec<u32>> = Vec::new();
+    for &time in query_times {
+        match runs.last_mut() {
+            Some(run)
+                if time - run[run.len() - 1] <= MAX_GAP_MS && !split(run[run.len() - 1], time) =>
+            {
+                run.push(time)
+            }
+            _ => runs.push(vec![time]),
+        }
+    }
+    runs
+}
+
+fn candidates<S: FingerprintStore + ?Sized>(
+    store: &S,
+    fingerprints: &[Fingerprint],
+) -> Result<Vec<Candidate>> {
+    let mut candidates = Vec::new();
+    for (song_id, matches) in join_matches(store, fingerprints)? {
+        let mut offsets = matches
+            .iter()
+            .map(|m| (m.reference_time as i64 - m.query_time as i64, m.query_time))
+            .collect::<Vec<_>>();
+        offsets.sort_unstable();
+
+        // Offsets closer than OFFSET_BIN to the previous one are the same alignment
+        for cluster in offsets.chunk_by(|a, b| b.0 - a.0 <= OFFSET_BIN as i64) {
+            if cluster.len() < MIN_SEGMENT_MATCHES {
+                continue;
+            }
+            let offset = cluster[cluster.len() / 2].0;
+            let query_times = unique_sorted(cluster.iter().map(|&(_, time)| time));
+            for run in runs(&query_times, |_, _| false) {
+                if run.len() >= MIN_SEGMENT_MATCHES {
+                    candidates.push(Candidate {
+                        song_id,
+                        offset,
+                        query_times: run,
+                    });
+                }
+            }
+        }
+    }
+    Ok(candidates)
+}
+
+// Cuts the timeline of the query into the songs it plays, in query order
+pub fn segment<S: FingerprintStore + ?Sized>(
+    store: &S,
+    fingerprints: &[Fingerprint],
+    min_confidence: f32,
+) -> Result<Vec<Segment>> {
+    let mut candidates = candidates(store, fingerprints)?;
+    candidates
+        .sort_unstable_by_key(|c| (Reverse(c.query_times.len()), c.query_times[0], c.song_id));
+
+    // Stronger candidates claim their part of the query first, weaker ones keep what is left
+    let mut claimed: Vec<(u32, u32)> = Vec::new();
+    let mut accepted = Vec::new();
+    for candidate in candidates {
+        let is_claimed = |time: u32| {
+            claimed
+                .iter()
+                .any(|&(start, end)| start <= time && time <= end)
+        };
+        let free = candidate
+            .query_times
+            .iter()
+            .copied()
+            .filter(|&time| !is_claimed(time))
+            .collect::<Vec<_>>();
+        let crosses_claimed =
+            |a: u32, b: u32| claimed.iter().any(|&(start, end)| a < start && end < b);
+
+        let mut kept = Vec::new();
+        for run in runs(&free, crosses_claimed) {
+            if run.len() >= MIN_SEGMENT_MATCHES {
+                kept.push((run[0], run[run.len() - 1], run.len()));
+            }
+        }
+        for (start, end, score) in kept {
+            claimed.push((start, end));
+            accepted.push((candidate.song_id, candidate.offset, start, end, score));
+        }
+    }
+
+    // Every anchor of the query between the ends of a segment can match once
+    let anchors = unique_sorted(fingerprints.iter().map(|fp| fp.anchor_time));
+    let anchors_between = |start: u32, end: u32| {
+        anchors.partition_point(|&t| t <= end) - anchors.partition_point(|&t| t < start)
+    };
+
+    accepted.sort_unstable_by_key(|a| a.2);
+    Ok(accepted
+        .into_iter()
+        .filter_map(|(song_id, offset, start, end, score)| {
+            let confidence =
+                coverage_confidence(score as f32 / anchors_between(start, end).max(1) as f32);
+            if confidence < min_confidence {
+                return None;
+            }
+            store.get_song(song_id).ok().map(|data| Segment {
+                data,
+                query_start: start as f32 / 1000.0,
+                query_end: end as f32 / 1000.0,
+                reference_offset: offset as f32 / 1000.0,
+                score,
+                confidence,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{
+        db::DbClient,
+        fingerprint::generate_fingerprint,
+        test_utils::{TIME_BASE, excerpt, peaks},
+    };
+
+    #[test]
+    fn test_segment_mix() {
+        let mut store = DbClient::new(&PathBuf::from(":memory:")).unwrap();
+        for seed in 1..=3 {
+            let song_id = store
+                .insert_song(&SongData {
+                    title: seed.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            let fingerprints = generate_fingerprint(peaks(seed, 0..200), TIME_BASE);
+            store.insert_fingerprints(song_id, &fingerprints).unwrap();
+        }
+
+        // Frames 0..100 of song 1, then frames 50..150 of song 2 and song 1 again from frame 100
+        let mix = [
+            excerpt(1, 0..100, 0),
+            excerpt(2, 50..150, 100),
+            excerpt(1, 100..200, 200),
+        ]
+        .concat();
+        let timeline = segment(&store, &generate_fingerprint(mix, TIME_BASE), 0.5).unwrap();
+
+        let titles = timeline
+            .iter()
+            .map(|s| s.data.title.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(titles, ["1", "2", "1"]);
+        let seconds = |frame| TIME_BASE.frame_to_ms(frame) as f32 / 1000.0;
+        for (segment, start) in timeline.iter().zip([0, 100, 200]) {
+            assert!(segment.query_start >= seconds(start) - 0.05);
+            assert!(segment.query_end <= seconds(start + 100));
+        }
+        for pair in timeline.windows(2) {
+            assert!(pair[0].query_end < pair[1].query_start);
+        }
+        assert!((timeline[1].reference_offset - (seconds(50) - seconds(100))).abs() < 0.05);
+        assert!((timeline[2].reference_offset - (seconds(100) - seconds(200))).abs() < 0.05);
+    }
+}
@@ -1,4 +1,4 @@
-use std::f32::consts::PI;
+use std::{cmp::Ordering, f32::consts::PI};
 
 use microfft::Complex32;
 
@@ -163,6 +163,9 @@ pub fn generate_spectrogram(
     let mut start: usize = 0;
 
     let mut spectrogram = Vec::new();
+    if sample.len() < w_size {
+        return spectrogram;
+    }
 
     while start < sample.len() {
         if start + w_size > sample.len() {
@@ -184,13 +187,56 @@ pub fn generate_spectrogram(
     spectrogram
 }
 
-#[derive(Debug)]
+// Converts frame indices to milliseconds, the only place where peak times leave integer space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBase {
+    // Distance between consecutive frames in samples
+    pub hop_size: usize,
+    pub sample_rate: usize,
+}
+
+impl TimeBase {
+    pub fn frame_to_ms(&self, frame: u32) -> u32 {
+        if self.sample_rate == 0 {
+            return 0;
+        }
+        (frame as u64 * self.hop_size as u64 * 1000 / self.sample_rate as u64) as u32
+    }
+}
+
+// Peaks are ordered by frame, then frequency bin, then magnitude
+#[derive(Debug, Clone, Copy)]
 pub struct Peak {
-    pub time: f32,
+    // Index of the spectrogram window the peak was found in
+    pub frame: u32,
     pub freq: u32,
+    pub magnitude: f32,
+}
+
+impl PartialEq for Peak {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
 }
 
-pub fn filter_spectrogram(spectrogram: &mut Vec<FFTWindow>, sample_rate: usize) -> Vec<Peak> {
+impl Eq for Peak {}
+
+impl PartialOrd for Peak {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Peak {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.frame
+            .cmp(&other.frame)
+            .then(self.freq.cmp(&other.freq))
+            .then(self.magnitude.total_cmp(&other.magnitude))
+    }
+}
+
+pub fn filter_spectrogram(spectrogram: &mut Vec<FFTWindow>) -> Vec<Peak> {
     let bands = [
         (20, 30),
         (30, 40),
@@ -203,7 +249,7 @@ pub fn filter_spectrogram(spectrogram: &mut Vec<FFTWindow>, sample_rate: usize)
 
     let mut peaks = Vec::new();
 
-    for window in spectrogram.iter() {
+    for (frame, window) in spectrogram.iter().enumerate() {
         let mut strongest_bins = Vec::with_capacity(bands.len());
 
         for &(start, end) in &bands {
@@ -235,10 +281,10 @@ pub fn filter_spectrogram(spectrogram: &mut Vec<FFTWindow>, sample_rate: usize)
 
         for (bin_index, complex) in strongest_bins {
             if complex.norm_sqr() > threshold {
-                let time_in_seconds = window.start_idx as f32 / sample_rate as f32;
                 peaks.push(Peak {
-                    time: time_in_seconds,
+                    frame: frame as u32,
                     freq: bin_index as u32,
+                    magnitude: complex.norm_sqr(),
                 });
             }
         }
@@ -246,3 +292,96 @@ pub fn filter_spectrogram(spectrogram: &mut Vec<FFTWindow>, sample_rate: usize)
 
     peaks
 }
+
+/*
+    Dense music (electronic, orchestral) produces far more peaks than sparse acoustic tracks, and every peak
+    anchors NEIGHBORHOOD_SIZE hashes. The controller caps the number of peaks in every one-second bucket,
+    keeping the strongest ones, so the hash rate stays within a budget whatever the material.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DensityController {
+    pub peaks_per_second: usize,
+}
+
+impl DensityController {
+    pub fn new(peaks_per_second: usize) -> Self {
+        DensityController { peaks_per_second }
+    }
+
+    pub fn apply(&self, peaks: Vec<Peak>, time_base: TimeBase) -> Vec<Peak> {
+        let mut buckets: Vec<Vec<Peak>> = Vec::new();
+        for peak in peaks {
+            let bucket = (time_base.frame_to_ms(peak.frame) / 1000) as usize;
+            if bucket >= buckets.len() {
+                buckets.resize_with(bucket + 1, Vec::new);
+            }
+            buckets[bucket].push(peak);
+        }
+
+        let mut kept = Vec::new();
+        for mut bucket in buckets {
+            bucket.sort_by(|a, b| b.magnitude.total_cmp(&a.magnitude));
+            bucket.truncate(self.peaks_per_second);
+            kept.extend(bucket);
+        }
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One frame every 100ms
+    const TIME_BASE: TimeBase = TimeBase {
+        hop_size: 100,
+        sample_rate: 1000,
+    };
+
+    fn peak(frame: u32, magnitude: f32) -> Peak {
+        Peak {
+            frame,
+            freq: 100,
+            magnitude,
+        }
+    }
+
+    #[test]
+    fn test_density_keeps_strongest_per_second() {
+        let peaks = vec![peak(1, 1.0), peak(5, 3.0), peak(9, 2.0), peak(12, 0.5)];
+        let kept = DensityController::new(2).apply(peaks, TIME_BASE);
+        let magnitudes = kept.iter().map(|p| p.magnitude).collect::<Vec<_>>();
+        assert_eq!(magnitudes, vec![3.0, 2.0, 0.5]);
+    }
+
+    #[test]
+    fn test_density_under_budget_keeps_everything() {
+        let peaks = vec![peak(1, 1.0), peak(25, 3.0)];
+        assert_eq!(DensityController::new(10).apply(peaks, TIME_BASE).len(), 2);
+    }
+
+    #[test]
+    fn test_zero_sample_rate_maps_to_zero() {
+        let time_base = TimeBase {
+            hop_size: 512,
+            sample_rate: 0,
+        };
+        assert_eq!(time_base.frame_to_ms(42), 0);
+    }
+
+    #[test]
+    fn test_peak_total_order() {
+        let mut peaks = [peak(2, 1.0), peak(1, f32::NAN), peak(1, 0.5)];
+        peaks.sort();
+        assert_eq!(
+            peaks.iter().map(|p| p.frame).collect::<Vec<_>>(),
+            vec![1, 1, 2]
+        );
+        assert_eq!(peaks[0].magnitude, 0.5);
+    }
+
+    #[test]
+    fn test_short_sample_has_no_windows() {
+        assert!(generate_spectrogram(&[0.0; 10], WindowSize::S1024, 512).is_empty());
+    }
+}
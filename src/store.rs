@@ -0,0 +1,73 @@
+use crate::{
+    db::{BitErrorRanking, SongData, SongStats},
+    error::{Error, Result},
+    fingerprint::{Fingerprint, FingerprintData},
+    philips::SubFingerprint,
+};
+
+/*
+    Storage of songs and their landmark fingerprints. The matcher in `ranking` only goes through
+    this trait, `DbClient` is the SQLite implementation. Philips sub-fingerprints are only kept by
+    `DbClient`, other stores can't search them.
+*/
+pub trait FingerprintStore {
+    fn insert_song(&mut self, song_data: &SongData) -> Result<i32>;
+
+    fn insert_fingerprints(&mut self, song_id: i32, fingerprints: &[Fingerprint]) -> Result<()>;
+
+    /*
+        Calls `visit` with every stored fingerprint whose address is one of `addresses`, ordered by
+        (address, anchor_address, anchor_time, song_id) like the SQLite primary key. `addresses` may
+        be in any order and contain duplicates, and there is no limit on its length.
+    */
+    fn for_each_match(
+        &self,
+        addresses: &[u32],
+        visit: &mut dyn FnMut(FingerprintData) -> Result<()>,
+    ) -> Result<()>;
+
+    // Collects `for_each_match`, for callers that need every row at once
+    fn lookup_addresses(&self, addresses: &[u32]) -> Result<Vec<FingerprintData>> {
+        let mut rows = Vec::new();
+        self.for_each_match(addresses, &mut |row| {
+            rows.push(row);
+            Ok(())
+        })?;
+        Ok(rows)
+    }
+
+    fn get_song(&self, song_id: i32) -> Result<SongData>;
+
+    // The `rank` songs with the lowest bit error rate, like `DbClient::search_philips`
+    fn search_sub_fingerprints(
+        &self,
+        _sub_fingerprints: Vec<SubFingerprint>,
+        _rank: usize,
+        _min_confidence: f32,
+    ) -> Result<Vec<BitErrorRanking>> {
+        Err(Error::Unsupported(
+            "this store doesn't hold Philips fingerprints".to_string(),
+        ))
+    }
+
+    // Removes the song and all of its fingerprints, fails if there is no such song
+    fn remove_song(&mut self, song_id: i32) -> Result<()>;
+
+    fn stats(&self) -> Result<Vec<SongStats>>;
+}
+
+pub(crate) fn unique_sorted(values: impl IntoIterator<Item = u32>) -> Vec<u32> {
+    let mut values = values.into_iter().collect::<Vec<_>>();
+    values.sort_unstable();
+    values.dedup();
+    values
+}
+
+// Order in which `for_each_match` delivers the fingerprints of one address
+pub(crate) fn posting_order(row: &FingerprintData) -> (u32, u32, i32) {
+    (
+        row.fingerprint.anchor_address,
+        row.fingerprint.anchor_time,
+        row.song_id,
+    )
+}
@@ -0,0 +1,47 @@
+use std::ops::Range;
+
+use crate::spectrogram::{Peak, TimeBase};
+
+// Songs shared by the tests of the modules that search fingerprints, made of peaks so no audio is needed
+
+pub const TIME_BASE: TimeBase = TimeBase {
+    hop_size: 512,
+    sample_rate: 11025,
+};
+
+// 441 frames are exactly 20480ms, a passage moved by a multiple of it keeps the delta times of its fingerprints
+pub const WHOLE_MS_FRAMES: u32 = 441;
+
+// Finalizer of MurmurHash3, neighbouring inputs give unrelated outputs
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85ebca6b);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xc2b2ae35);
+    x ^ (x >> 16)
+}
+
+// Two peaks per frame at hashed frequencies, nothing in common between seeds
+pub fn peaks(seed: u32, frames: Range<u32>) -> Vec<Peak> {
+    frames
+        .flat_map(|frame| {
+            (0..2).map(move |i| Peak {
+                frame,
+                freq: mix(frame * 2 + i + seed * 4096) >> 23,
+                magnitude: 1.0,
+            })
+        })
+        .collect()
+}
+
+// The peaks of `frames` of a song, moved to start at frame `start`
+pub fn excerpt(seed: u32, frames: Range<u32>, start: u32) -> Vec<Peak> {
+    let first = frames.start;
+    peaks(seed, frames)
+        .into_iter()
+        .map(|peak| Peak {
+            frame: peak.frame - first + start,
+            ..peak
+        })
+        .collect()
+}
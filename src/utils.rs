@@ -55,7 +55,7 @@ mod tests {
     #[test]
     fn test_empty() {
         let empty: Vec<i32> = vec![];
-        assert_eq!(longest_increasing_subsequence(&empty), vec![]);
+        assert_eq!(longest_increasing_subsequence(&empty), Vec::<i32>::new());
     }
 
     #[test]